@@ -0,0 +1,57 @@
+//! Output auto-off timer, switches an output off a set time after it was switched on
+
+use crate::clock::Millis;
+
+/// Per channel timer settings and countdown
+pub struct AutoOff {
+    pub enabled: bool,
+    pub duration_ms: u64,
+    pub remaining_ms: Option<u64>, // None while the output is off
+    on_since: Option<Millis>,
+}
+
+impl AutoOff {
+    pub const fn new() -> Self {
+        AutoOff {
+            enabled: false,
+            duration_ms: 60 * 60 * 1000,
+            remaining_ms: None,
+            on_since: None,
+        }
+    }
+
+    /// From a project file, None switches it off
+    pub fn set(&mut self, duration_ms: Option<u64>) {
+        match duration_ms {
+            Some(d) => {
+                self.enabled = true;
+                self.duration_ms = d;
+            }
+            None => self.enabled = false,
+        }
+    }
+
+    /// Counts down while the output is on, true when the time is up.
+    /// A changed duration applies to the running countdown.
+    pub fn tick(&mut self, now: Millis, out: Option<bool>) -> bool {
+        match out {
+            _ if !self.enabled => self.on_since = None,
+            Some(true) => {
+                let since = *self.on_since.get_or_insert(now);
+                let elapsed = now.saturating_sub(since);
+                if elapsed >= self.duration_ms {
+                    self.on_since = None;
+                    self.remaining_ms = None;
+                    return true;
+                }
+                self.remaining_ms = Some(self.duration_ms - elapsed);
+                return false;
+            }
+            Some(false) => self.on_since = None,
+            None => return false, // output just switched, wait for the next poll
+        }
+
+        self.remaining_ms = None;
+        false
+    }
+}
@@ -29,8 +29,9 @@ use heapless::{consts::*, String, Vec};
 
 use power_supply_ieee488_gpib_controller::*;
 use power_supply_ieee488_gpib_controller::{
-    button::*, display::*, line::*, model::*, prelude::*, protocol::*, rotary_encoder::*,
-    sdcard::*, time::*, uart_serial::*,
+    button::*, calibration::*, clock::*, config::*, datalog::*, directive::*, display::*,
+    limits::*, line::*, model::*, prelude::*, protocol::*, rotary_encoder::*, script::*, sdcard::*,
+    sequencer::*, session::*, snapshot::*, time::*, uart_serial::*,
 };
 
 // https://github.com/stm32-rs/stm32f4xx-hal/blob/master/examples/usb_serial.rs
@@ -47,12 +48,14 @@ const APP: () = {
         uart_serial: UartSerial,
         display: Display,
         sdcard: SDCard,
+        rtc: Rtc,
 
         uart_rx_buf: Vec<u8, U32>,
         usb_rx_buf: Vec<u8, U32>,
 
         query: Option<Query>,
         query_idx: usize,
+        ping_cycles: u32,
 
         btn_pause: Button<PauseButtonPin>,
         btn_encoder: Button<EncoderButtonPin>,
@@ -149,24 +152,11 @@ const APP: () = {
 
         let usb_serial = UsbSerial::new(usb_bus);
 
-        ps.set_ui_loading("uart_serial");
+        ps.set_ui_loading("rtc");
         display.render(&ps).unwrap();
-        ifcfg!("bin_info", hprintln!("uart_serial"));
-
-        let pin_tx = gpioa.pa15.into_alternate_af7();
-        let pin_rx = gpioa.pa10.into_alternate_af7();
-
-        let mut uart_serial = UartSerial::new(
-            serial::Serial::usart1(
-                device.USART1,
-                (pin_tx, pin_rx),
-                serial::config::Config::default().baudrate(115_200.bps()),
-                clocks,
-            )
-            .unwrap(),
-        );
+        ifcfg!("bin_info", hprintln!("rtc"));
 
-        uart_serial.init();
+        let rtc = Rtc::init(device.RTC);
 
         ps.set_ui_loading("sd_card");
         display.render(&ps).unwrap();
@@ -187,11 +177,44 @@ const APP: () = {
             clocks,
         );
 
-        let sdcard = SDCard::new(embedded_sdmmc::Controller::new(
+        // there may be no card, it's picked up once it's inserted
+        let mut sdcard = SDCard::new(embedded_sdmmc::Controller::new(
             embedded_sdmmc::SdMmcSpi::new(sd_spi, sd_cs),
-            DummyTimeSource {},
-        ))
-        .unwrap();
+            rtc,
+        ));
+
+        // before the serial port, it has the baud rate
+        let (config, bad_line) = Config::load(&mut sdcard);
+        ps.set_config(config);
+        match bad_line {
+            Some(line_no) => ps.show_file_error(AppError::ProjectFileError, CONFIG_FILE, line_no),
+            None => (),
+        }
+
+        // a clock without a backup battery starts from the configured time
+        match config.time {
+            Some(dt) if !rtc.is_set() => rtc.set(&dt).unwrap_or(()),
+            _ => (),
+        }
+
+        ps.set_ui_loading("uart_serial");
+        display.render(&ps).unwrap();
+        ifcfg!("bin_info", hprintln!("uart_serial"));
+
+        let pin_tx = gpioa.pa15.into_alternate_af7();
+        let pin_rx = gpioa.pa10.into_alternate_af7();
+
+        let mut uart_serial = UartSerial::new(
+            serial::Serial::usart1(
+                device.USART1,
+                (pin_tx, pin_rx),
+                serial::config::Config::default().baudrate(config.baud.bps()),
+                clocks,
+            )
+            .unwrap(),
+        );
+
+        uart_serial.init();
 
         ps.set_ui_loading("buttons");
         display.render(&ps).unwrap();
@@ -208,7 +231,7 @@ const APP: () = {
         // RM0383, Figure 17. Selecting an alternate function onSTM32F411xC/E
         gpioa.pa8.into_alternate_af1().internal_pull_up(true);
         gpioa.pa9.into_alternate_af1().internal_pull_up(true);
-        let rotary_encoder = RotaryEncoder::new(device.TIM1);
+        let rotary_encoder = RotaryEncoder::new(device.TIM1, config.encoder_steps);
 
         ps.set_ui_loading("resources");
         display.render(&ps).unwrap();
@@ -224,10 +247,12 @@ const APP: () = {
             uart_serial,
             display,
             sdcard,
+            rtc,
             uart_rx_buf,
             usb_rx_buf,
             query: None,
             query_idx: 0,
+            ping_cycles: SYS_FREQ.0 / config.poll_hz,
 
             btn_pause,
             btn_encoder,
@@ -240,6 +265,7 @@ const APP: () = {
         led,
         display,
         sdcard,
+        rtc,
         usb_serial,
         uart_serial,
         usb_rx_buf,
@@ -258,7 +284,7 @@ const APP: () = {
             il.handle_state_ok();
         }
     }
-    #[task(resources = [query, query_idx],
+    #[task(resources = [query, query_idx, ping_cycles],
                schedule = [ping],
                priority = 1)]
     fn ping(cx: ping::Context) {
@@ -270,7 +296,7 @@ const APP: () = {
         }
 
         cx.schedule
-            .ping(cx.scheduled + Duration::from_cycles(SYS_FREQ.0 / 16))
+            .ping(cx.scheduled + Duration::from_cycles(*cx.resources.ping_cycles))
             .unwrap();
     }
 
@@ -331,8 +357,83 @@ const APP: () = {
     }
 };
 
+/// Project file lines to the instrument, responses back
+struct FileLineIO<'l, 'a> {
+    uart_serial: &'l mut resources::uart_serial<'a>,
+    uart_rx_buf: &'l mut resources::uart_rx_buf<'a>,
+    uart_line_buf: &'l mut Vec<u8, U64>,
+    usb_serial: &'l mut resources::usb_serial<'a>,
+    clock: &'l mut Clock,
+}
+
+impl<'l, 'a> FileLineIO<'l, 'a> {
+    /// Send a line, collect the response of a query (echoed to the USB host),
+    /// then ask for ERR? so that loading stops at the first line the instrument didn't take
+    fn send_checked(&mut self, line: &[u8]) -> Result<(), AppError> {
+        let cmd = core::str::from_utf8(line)
+            .map_err(|_| AppError::ProjectFileError)?
+            .trim();
+        if cmd.is_empty() || cmd.starts_with('#') {
+            return Ok(()); // blank or a comment
+        }
+
+        // serial adapter commands, nothing for the instrument to check
+        if cmd.starts_with("++") {
+            self.write_line(cmd)?;
+            asm::delay(COMMAND_DELAY_MS * SYS_CYCLES_PER_MILLISECOND);
+            return Ok(());
+        }
+
+        self.uart_rx_buf.lock(|b| b.clear()); // anything left over isn't ours
+
+        if cmd.contains('?') {
+            let resp = self.query(cmd)?;
+            let mut buf: String<U128> = String::new();
+            write!(buf, "{}\t{}\r\n", cmd, resp).map_err(|_| AppError::ProjectFileError)?;
+            self.usb_serial.lock(|s| s.write(&buf.into_bytes()))?;
+        } else {
+            self.write_line(cmd)?;
+        }
+
+        match self.query("ERR?")?.parse::<u8>() {
+            Ok(0) => Ok(()),
+            Ok(code) => Err(AppError::InstrumentError(code)),
+            Err(_) => Err(AppError::ParseError),
+        }
+    }
+
+    fn write_line(&mut self, cmd: &str) -> Result<(), AppError> {
+        self.uart_serial.lock(|us| {
+            us.write_buf(cmd.as_bytes())?;
+            us.write_buf_flush(b"\r\n")
+        })
+    }
+
+    /// Send a query, wait for the response (no more than a query timeout)
+    fn query(&mut self, cmd: &str) -> Result<String<U64>, AppError> {
+        self.write_line(cmd)?;
+        self.write_line("++read eoi")?;
+
+        let uart_line_buf = &mut self.uart_line_buf;
+        uart_line_buf.clear();
+
+        let start = self.clock.now();
+        while !self.uart_rx_buf.lock(|b| fill_until_eol(uart_line_buf, b)) {
+            if self.clock.now() - start > QUERY_TIMEOUT_MS as u64 {
+                return Err(AppError::NoResponse);
+            }
+        }
+
+        let mut resp = String::new();
+        to_str_skip_whitespace(uart_line_buf, &mut resp)?;
+        uart_line_buf.clear();
+        Ok(resp)
+    }
+}
+
 struct IdleLoop<'a> {
     led: &'a mut LedPin,
+    clock: Clock,
     usb_serial: resources::usb_serial<'a>,
     usb_rx_buf: resources::usb_rx_buf<'a>,
     uart_serial: resources::uart_serial<'a>,
@@ -341,11 +442,11 @@ struct IdleLoop<'a> {
     query: resources::query<'a>,
     query_sent: bool,
 
-    next_command: String<U64>,
-
     ps: &'a mut PS,
     display: &'a mut Display,
     sdc: &'a mut SDCard,
+    card_checked: Millis,
+    rtc: Rtc,
     usb_line_buf: Vec<u8, U64>,
     usb_eol: bool,
     uart_line_buf: Vec<u8, U64>,
@@ -360,6 +461,7 @@ impl<'a> IdleLoop<'a> {
     pub fn new(cx: idle::Context<'a>) -> Self {
         IdleLoop {
             led: cx.resources.led,
+            clock: Clock::new(),
             usb_serial: cx.resources.usb_serial,
             usb_rx_buf: cx.resources.usb_rx_buf,
             uart_serial: cx.resources.uart_serial,
@@ -367,11 +469,12 @@ impl<'a> IdleLoop<'a> {
 
             query: cx.resources.query,
             query_sent: false,
-            next_command: String::new(),
 
             ps: cx.resources.ps,
             display: cx.resources.display,
             sdc: cx.resources.sdcard,
+            card_checked: 0,
+            rtc: *cx.resources.rtc,
             usb_line_buf: Vec::new(),
             usb_eol: false,
             uart_line_buf: Vec::new(),
@@ -422,19 +525,47 @@ impl<'a> IdleLoop<'a> {
 
         self.render_loading("BOOT")?;
 
-        self.show_err_ok(|slf| {
-            let sdc = &mut slf.sdc;
-            // we won't receive anything while sending the whole file but that's Ok
-            slf.uart_serial
-                .lock(|us| sdc.send_boot_file(|buf| us.write_buf_flush(buf)))
-        });
+        self.show_err_ok(|il| il.setup_adapter());
+
+        // saved calibration (there may be none), then the boot file
+        let mut prog = None;
+        if self.sdc.is_present() {
+            self.run_file(CAL_FILE, &mut None).ok();
+            self.load_file("BOOT", &mut prog);
+        }
+
+        // last session, unless the boot file started a program
+        let session = match prog {
+            None if self.sdc.is_present() => Session::load(self.sdc),
+            _ => None,
+        };
 
         self.drain_uart_rx(); // in case there's any junk from loading a file
         self.render_loading("DONE")?;
-        self.ps.set_ui_info_screen();
+        self.set_ui_after_loading(prog);
+        match (session, &self.ps.ui) {
+            (Some(s), UI::InfoScreen(_)) => self.ps.ui = UI::RestoreScreen(RestoreScreen::new(s)),
+            _ => self.ps.session.enabled = true,
+        }
         Ok(())
     }
 
+    /// Serial adapter defaults, instrument address from the config
+    fn setup_adapter(&mut self) -> Result<(), AppError> {
+        let mut addr: String<U16> = String::new();
+        write!(addr, "++addr {}", self.ps.config.gpib_addr)?;
+
+        let mut io = FileLineIO {
+            uart_serial: &mut self.uart_serial,
+            uart_rx_buf: &mut self.uart_rx_buf,
+            uart_line_buf: &mut self.uart_line_buf,
+            usb_serial: &mut self.usb_serial,
+            clock: &mut self.clock,
+        };
+        io.send_checked(b"++default")?;
+        io.send_checked(addr.as_bytes())
+    }
+
     pub fn load_project_file<'f>(&mut self, fname: &'f str) -> Result<(), AppError> {
         ifcfg!("bin_info", hprintln!("load_project_file {}", fname));
 
@@ -442,22 +573,97 @@ impl<'a> IdleLoop<'a> {
 
         self.render_loading(".,.,.")?;
 
-        self.show_err_ok(|slf| {
-            let sdc = &mut slf.sdc;
-            // we won't receive anything while sending the whole file but that's Ok
-            slf.uart_serial
-                .lock(|us| sdc.send_file(fname, |buf| us.write_buf_flush(buf)))
-        });
+        let mut prog = None;
+        self.load_file(fname, &mut prog);
 
         self.drain_uart_rx(); // in case there's any junk from loading a file
         self.render_loading("DONE")?;
 
-        self.ps.set_ui_info_screen();
+        self.set_ui_after_loading(prog);
 
         ifcfg!("bin_info", hprintln!("load_project_file DONE {}", fname));
         Ok(())
     }
 
+    /// Run a project file, errors are shown with the line they came from
+    fn load_file(&mut self, fname: &str, prog: &mut Option<Program>) {
+        match self.run_file(fname, prog) {
+            Err((e, line_no)) => self.ps.show_file_error(e, fname, line_no),
+            Ok(()) => (),
+        }
+    }
+
+    /// Send instrument commands from a file one line at a time, apply controller directives,
+    /// lines after '@sequence' or '@script' are parsed into a program to run.
+    /// Errors come with the line number (1 based)
+    fn run_file(&mut self, fname: &str, prog: &mut Option<Program>) -> Result<(), (AppError, u16)> {
+        let sdc = &mut self.sdc;
+        let ps = &mut self.ps;
+        let rtc = self.rtc;
+        ps.loaded_mode = None;
+
+        let mut io = FileLineIO {
+            uart_serial: &mut self.uart_serial,
+            uart_rx_buf: &mut self.uart_rx_buf,
+            uart_line_buf: &mut self.uart_line_buf,
+            usb_serial: &mut self.usb_serial,
+            clock: &mut self.clock,
+        };
+
+        let mut line_no = 0u16;
+
+        let res = sdc
+            .read_lines(fname, |line| {
+                line_no = line_no.saturating_add(1);
+                match prog.as_mut() {
+                    Some(p) => p.parse_line(line_no, line),
+                    None => match Directive::parse(line)? {
+                        Some(Directive::Sequence { cycles }) => {
+                            *prog = Some(Program::Sequence(Sequence::new(cycles)));
+                            Ok(())
+                        }
+                        Some(Directive::Script) => {
+                            *prog = Some(Program::Script(Script::new()));
+                            Ok(())
+                        }
+                        Some(Directive::Time(dt)) => rtc.set(&dt),
+                        Some(d) => ps.apply_directive(d),
+                        None => {
+                            check_line(line, &ps.ch1.limits, &ps.ch2.limits)?;
+                            let mut rest: String<U128> = String::new();
+                            let changed = soft_start_line(
+                                line,
+                                &mut ps.ch1,
+                                &mut ps.ch2,
+                                &mut ps.commands,
+                                &mut rest,
+                            )?;
+                            io.send_checked(if changed { rest.as_bytes() } else { line })
+                        }
+                    },
+                }
+            })
+            .and_then(|_| prog.as_ref().map_or(Ok(()), |p| p.validate()));
+
+        // don't run half of a broken sequence or script
+        if res.is_err() {
+            *prog = None;
+        }
+
+        res.map_err(|e| (e, line_no))
+    }
+
+    #[inline]
+    fn set_ui_after_loading(&mut self, prog: Option<Program>) {
+        match prog {
+            Some(p) => self.ps.set_ui_program_screen(p),
+            None => match self.ps.loaded_mode.take() {
+                Some(item) => self.ps.open_screen(item),
+                None => self.ps.set_ui_info_screen(),
+            },
+        }
+    }
+
     pub fn try_read_lines(&mut self) {
         self.usb_serial.lock(|s| s.poll());
 
@@ -482,6 +688,13 @@ impl<'a> IdleLoop<'a> {
             )
         );
 
+        // clock lines are answered by the controller in any mode
+        if self.usb_eol && is_time_line(&self.usb_line_buf) {
+            self.show_err_ok(|il| il.handle_usb_time());
+            self.usb_line_buf.clear();
+            self.usb_eol = false;
+        }
+
         // 1st line of input switches into UART serial adapter mode
         if self.usb_eol {
             self.ps.set_ui_usb_serial();
@@ -491,6 +704,28 @@ impl<'a> IdleLoop<'a> {
         }
     }
 
+    /// `@time` reads the clock, `@time <date> <time>` sets it, answers `TIME\t<date> <time>`
+    fn handle_usb_time(&mut self) -> Result<(), AppError> {
+        let res = match Directive::parse(&self.usb_line_buf) {
+            Ok(Some(Directive::Time(dt))) => self.rtc.set(&dt),
+            _ if core::str::from_utf8(&self.usb_line_buf)
+                .map_or(false, |l| l.trim() == "@time") =>
+            {
+                Ok(())
+            }
+            _ => Err(AppError::ParseError),
+        };
+
+        let mut buf: String<U64> = String::new();
+        match (res, self.rtc.now()) {
+            (Err(AppError::ParseError), _) => write!(buf, "TIME\tbad date/time\r\n")?,
+            (Err(_), _) => write!(buf, "TIME\tno clock\r\n")?,
+            (Ok(()), Some(dt)) => write!(buf, "TIME\t{}\r\n", dt)?,
+            (Ok(()), None) => write!(buf, "TIME\tnot set\r\n")?,
+        }
+        self.usb_serial.lock(|us| us.write(&buf.into_bytes()))
+    }
+
     /// Read/throw away what's currently in the buffer
     fn drain_uart_rx(&mut self) {
         let uart_line_buf = &mut self.uart_line_buf;
@@ -501,7 +736,8 @@ impl<'a> IdleLoop<'a> {
             uart_line_buf.clear();
         }
 
-        self.next_command.clear();
+        self.ps.commands.clear();
+        self.ps.link.idle();
         self.uart_eol = false;
         self.query_sent = false;
         self.query.lock(|qopt| qopt.take());
@@ -528,58 +764,314 @@ impl<'a> IdleLoop<'a> {
             }
         });
 
+        // a button press dismisses the error screen
+        if self.ps.error.is_some() && button_press.is_some() {
+            self.ps.clear_error();
+            return Ok(());
+        }
+
         match button_press {
             None => (),
             Some(pp) => {
-                if pp > MilliSeconds(700) {
-                    let pfs = ProjectFiles::new(self.sdc)?;
-                    self.ps.ui = UI::ProjectFiles(pfs);
+                if pp > self.ps.config.long_press {
+                    self.ps.stop_waveform()?;
+                    // no card (or files) is shown, the rest goes on
+                    self.show_err_ok(|il| {
+                        let pfs = ProjectFiles::new(il.sdc, &il.ps.project_dir, &il.ps.file_exts)?;
+                        il.ps.ui = UI::ProjectFiles(pfs);
+                        Ok(())
+                    });
                 }
             }
         }
 
+        let now = self.clock.now();
+        if now - self.card_checked >= CARD_CHECK_MS {
+            self.card_checked = now;
+            self.sdc.check_present();
+        }
+        if self.ps.card_present && !self.sdc.is_present() {
+            self.ps.datalog.card_removed();
+        }
+        self.ps.card_present = self.sdc.is_present();
+
+        self.ps.wall_time = self.rtc.now();
+        self.ps.tick(self.clock.now())?;
+        self.show_err_ok(|il| il.handle_log_io());
+        self.handle_session_io();
+
         match &mut self.ps.ui {
             UI::UILoading(_) => Ok(()),
             UI::USSBSerial => self.handle_state_usb_serial(),
-            UI::InfoScreen(is) => {
-                match button_press {
-                    None => (),
-                    Some(pp) => {
-                        if pp > MilliSeconds(100) {
-                            self.next_command.clear(); // replace previous command
-                            is.handle_on_off_button(&mut self.next_command)?;
+            UI::ProjectFiles(pfs) => {
+                let re_press_duration = self
+                    .btn_encoder
+                    .lock(|b| b.take_last_press(time::MilliSeconds(60)));
+                let selected = pfs.handle_rotary_encoder(
+                    self.sdc,
+                    re_press_duration,
+                    self.ps.config.short_press,
+                    encoder_change,
+                )?;
+                self.ps.project_dir.clone_from(&pfs.dir);
+                match selected {
+                    Some(path) => {
+                        let fp = FilePreview::new(
+                            self.sdc,
+                            path,
+                            &self.ps.ch1.limits,
+                            &self.ps.ch2.limits,
+                        )?;
+                        match core::mem::replace(&mut self.ps.ui, UI::UILoading(".,.,.")) {
+                            UI::ProjectFiles(pfs) => self.ps.ui = UI::FilePreview(fp, pfs),
+                            _ => (),
                         }
+                        Ok(())
                     }
+                    None => Ok(()),
                 }
-
-                is.ch1.sample_current_power();
-                is.ch2.sample_current_power();
-
-                IdleLoop::handle_state_info_screen(
-                    encoder_change,
-                    &mut self.btn_encoder,
-                    &mut self.usb_serial,
-                    &mut self.uart_serial,
-                    &mut self.uart_eol,
-                    &mut self.uart_line_buf,
-                    &mut self.query,
-                    &mut self.query_sent,
-                    &mut self.next_command,
-                    is,
-                )
             }
-            UI::ProjectFiles(pfs) => {
+            UI::FilePreview(fp, _) => {
                 let re_press_duration = self
                     .btn_encoder
                     .lock(|b| b.take_last_press(time::MilliSeconds(60)));
-                match pfs.handle_rotary_encoder(re_press_duration, encoder_change)? {
-                    Some(fname) => self.load_project_file(&fname),
+                let answer = if button_press.is_some() {
+                    Some(false)
+                } else {
+                    fp.handle_rotary_encoder(
+                        re_press_duration,
+                        self.ps.config.short_press,
+                        encoder_change,
+                    )
+                };
+                match answer {
+                    Some(true) => {
+                        let path = fp.path.clone();
+                        // limits may have changed since the preview
+                        match fp.meta.check(&self.ps.ch1.limits, &self.ps.ch2.limits) {
+                            Ok(()) => self.load_project_file(&path),
+                            Err((e, line_no)) => {
+                                self.ps.show_file_error(e, &path, line_no);
+                                Ok(())
+                            }
+                        }
+                    }
+                    Some(false) => {
+                        match core::mem::replace(&mut self.ps.ui, UI::UILoading(".,.,.")) {
+                            UI::FilePreview(_, pfs) => self.ps.ui = UI::ProjectFiles(pfs),
+                            _ => (),
+                        }
+                        Ok(())
+                    }
                     None => Ok(()),
                 }
             }
+            UI::SweepScreen(_) => {
+                self.handle_query()?;
+                self.handle_sweep_io()?;
+                self.handle_state_live_screen(encoder_change, button_press)
+            }
+            UI::CalScreen(_) => {
+                self.handle_query()?;
+                self.handle_cal_io()?;
+                self.handle_state_live_screen(encoder_change, button_press)
+            }
+            UI::SaveScreen(_) => {
+                self.handle_query()?;
+                self.handle_save_io()?;
+                self.handle_state_live_screen(encoder_change, button_press)
+            }
+            UI::ScriptScreen(_) => {
+                self.handle_query()?;
+                self.handle_script_io()?;
+                self.handle_state_live_screen(encoder_change, button_press)
+            }
+            _ => {
+                self.handle_query()?;
+                self.handle_state_live_screen(encoder_change, button_press)
+            }
         }
     }
 
+    /// Stream new sweep points to the USB host, save the results once it's done
+    fn handle_sweep_io(&mut self) -> Result<(), AppError> {
+        let sr = match &mut self.ps.ui {
+            UI::SweepScreen(ss) => &mut ss.run,
+            _ => return Ok(()),
+        };
+
+        while sr.streamed < sr.points.len() {
+            let mut buf: String<U64> = String::new();
+            buf.push_str("IV\t").map_err(|_| AppError::Duh)?;
+            sr.write_csv_line(sr.streamed, &mut buf)?;
+            self.usb_serial.lock(|s| s.write(&buf.into_bytes()))?;
+            sr.streamed += 1;
+        }
+
+        if sr.save_pending {
+            sr.save_pending = false; // a single attempt, errors are shown
+            let fname = self.sdc.next_file_name("IV", "CSV")?;
+
+            // header, then a line per point
+            let n = sr.points.len();
+            let mut row = 0;
+            self.sdc.write_file(&fname, |buf| {
+                if row == 0 {
+                    sr.write_csv_header(buf)?;
+                } else {
+                    sr.write_csv_line(row - 1, buf)?;
+                }
+                row += 1;
+                Ok(row <= n)
+            })?;
+
+            sr.saved = Some(fname);
+        }
+
+        Ok(())
+    }
+
+    /// Write buffered rows when it's time, into a new log file on the first write of a run
+    fn handle_log_io(&mut self) -> Result<(), AppError> {
+        let now = self.clock.now();
+        let log = &mut self.ps.datalog;
+
+        if !log.flush_due(now) {
+            return Ok(());
+        }
+
+        if log.fname.is_none() {
+            match self.sdc.next_file_name(LOG_FILE_PREFIX, LOG_FILE_EXT) {
+                Ok(fname) => log.fname = Some(fname),
+                Err(e) => return log.write_failed(now, e),
+            }
+        }
+
+        let res = match &log.fname {
+            Some(fname) => self.sdc.append_file(fname, log.pending()),
+            None => Ok(()),
+        };
+        match res {
+            Ok(()) => {
+                log.flushed();
+                Ok(())
+            }
+            Err(e) => log.write_failed(now, e), // card gone or full, tried again later
+        }
+    }
+
+    /// Saved in the background, a failed save is tried again later
+    fn handle_session_io(&mut self) {
+        let session = match self.ps.session.pending() {
+            Some(s) => s,
+            None => return,
+        };
+
+        let mut n = 0;
+        let res = self.sdc.write_file(SESSION_FILE, |buf| {
+            let more = session.write_line(n, buf)?;
+            n += 1;
+            Ok(more)
+        });
+        match res {
+            Ok(()) => self.ps.session.saved(session),
+            Err(_) => self.ps.session.save_failed(self.clock.now()),
+        }
+    }
+
+    /// Send a script's instrument command (between queries, like queued commands) or log line
+    fn handle_script_io(&mut self) -> Result<(), AppError> {
+        let sr = match &mut self.ps.ui {
+            UI::ScriptScreen(sr) => sr,
+            _ => return Ok(()),
+        };
+
+        match sr.pending.as_ref() {
+            Some((Output::Log, s)) => {
+                let mut buf: String<U80> = String::new();
+                write!(buf, "LOG\t{}\r\n", s)?;
+                self.usb_serial.lock(|us| us.write(&buf.into_bytes()))?;
+            }
+            Some((Output::Send, s)) => {
+                if self.query_sent || self.ps.link.is_lost() {
+                    return Ok(()); // next time around
+                }
+                let res = check_line(s.as_bytes(), &self.ps.ch1.limits, &self.ps.ch2.limits);
+                if res.is_err() {
+                    sr.stop();
+                    return res;
+                }
+                let mut rest: String<U64> = String::new();
+                let changed = soft_start_line(
+                    s.as_bytes(),
+                    &mut self.ps.ch1,
+                    &mut self.ps.ch2,
+                    &mut self.ps.commands,
+                    &mut rest,
+                )?;
+                let line = if changed { &rest } else { s };
+                if !line.is_empty() {
+                    let mut buf: String<U80> = String::new();
+                    write!(buf, "{}\r\n", line)?;
+                    self.uart_serial
+                        .lock(|us| us.write_buf_flush(buf.as_bytes()))?;
+                    asm::delay(COMMAND_DELAY_MS * SYS_CYCLES_PER_MILLISECOND);
+                }
+            }
+            None => (),
+        }
+
+        sr.pending = None;
+        Ok(())
+    }
+
+    /// Save the calibration after a change
+    fn handle_cal_io(&mut self) -> Result<(), AppError> {
+        let cs = match &mut self.ps.ui {
+            UI::CalScreen(cs) if cs.save_pending => cs,
+            _ => return Ok(()),
+        };
+        cs.save_pending = false;
+
+        let (cal1, cal2) = (self.ps.ch1.cal, self.ps.ch2.cal);
+        let mut targets = CAL_TARGETS.iter();
+        self.sdc.write_file(CAL_FILE, |buf| match targets.next() {
+            Some((ch, target)) => {
+                let cal = match ch {
+                    Channel::Ch1 => cal1.get(*target),
+                    Channel::Ch2 => cal2.get(*target),
+                };
+                cal.write_directive(*ch, *target, buf)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        })?;
+
+        cs.saved = true;
+        Ok(())
+    }
+
+    /// Write the current setpoints to a new project file
+    fn handle_save_io(&mut self) -> Result<(), AppError> {
+        let ss = match &mut self.ps.ui {
+            UI::SaveScreen(ss) if ss.save_pending => ss,
+            _ => return Ok(()),
+        };
+        ss.save_pending = false; // a single attempt, errors are shown
+
+        let snap = Snapshot::new(&self.ps.ch1, &self.ps.ch2, self.ps.wall_time)?;
+        let fname = self.sdc.next_file_name(SNAPSHOT_PREFIX, SNAPSHOT_EXT)?;
+        let mut n = 0;
+        self.sdc.write_file(&fname, |buf| {
+            let more = snap.write_line(n, buf)?;
+            n += 1;
+            Ok(more)
+        })?;
+
+        ss.saved = Some(fname);
+        Ok(())
+    }
+
     #[inline]
     fn handle_state_usb_serial(&mut self) -> Result<(), AppError> {
         let usb_line_buf = &mut self.usb_line_buf;
@@ -594,63 +1086,84 @@ impl<'a> IdleLoop<'a> {
         // Lock means we can't receive while writing but it's Ok
         // for this particular request/response protocol
         if self.usb_eol {
-            self.uart_serial
-                .lock(|s| s.write_buf_flush(&usb_line_buf))?;
+            let mut rest: String<U64> = String::new();
+            let res = match check_line(&usb_line_buf, &self.ps.ch1.limits, &self.ps.ch2.limits) {
+                Ok(()) => soft_start_line(
+                    &usb_line_buf,
+                    &mut self.ps.ch1,
+                    &mut self.ps.ch2,
+                    &mut self.ps.commands,
+                    &mut rest,
+                ),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(false) => self
+                    .uart_serial
+                    .lock(|s| s.write_buf_flush(&usb_line_buf))?,
+                Ok(true) if !rest.is_empty() => self.uart_serial.lock(|s| {
+                    s.write_buf(rest.as_bytes())?;
+                    s.write_buf_flush(b"\r\n")
+                })?,
+                _ => (),
+            }
             self.usb_line_buf.clear();
+            res?;
+        }
+
+        // soft-start ramps (and the rest of the queue) go out between the host's lines
+        if !self.ps.commands.is_empty() {
+            let limits_ok = self.ps.check_commands();
+            let mut cmdbuf: String<U256> = String::new();
+            self.ps.commands.drain_to_str(&mut cmdbuf)?;
+            if !cmdbuf.is_empty() {
+                // a line of its own, there's no query after it to end it
+                self.uart_serial.lock(|s| {
+                    s.write_buf(cmdbuf.trim_end_matches(';').as_bytes())?;
+                    s.write_buf_flush(b"\r\n")
+                })?;
+                asm::delay(COMMAND_DELAY_MS * SYS_CYCLES_PER_MILLISECOND);
+            }
+            limits_ok?;
         }
 
         Ok(())
     }
 
+    /// Send pending command, poll channel values
     #[inline]
-    fn handle_state_info_screen(
-        encoder_change: i16,
-        btn_encoder: &mut resources::btn_encoder<'a>,
-        usb_serial: &mut resources::usb_serial<'a>,
-        uart_serial: &mut resources::uart_serial<'a>,
-        uart_eol: &mut bool,
-        uart_line_buf: &mut Vec<u8, U64>,
-        query: &mut resources::query<'a>,
-        query_sent: &mut bool,
-        next_command: &mut String<U64>,
-        is: &mut InfoScreen,
-    ) -> Result<(), AppError> {
-        let (encoder_press, btn_encoder_is_pressed) = btn_encoder.lock(|b| {
-            if encoder_change != 0 {
-                b.cancel_last_press()
-            }
-            (
-                b.take_last_press(time::MilliSeconds(60)),
-                b.is_pressed(time::MilliSeconds(30)),
-            )
-        });
+    fn handle_query(&mut self) -> Result<(), AppError> {
+        let now = self.clock.now();
 
-        ifcfg!("bin_info", {
-            (match encoder_press {
-                None => Ok(()),
-                Some(e) => hprintln!("BTN E {}", e.0),
-            })
-            .and_then(|_| {
-                if encoder_change != 0 {
-                    hprintln!("RE {}", encoder_change)
-                } else {
-                    Ok(())
-                }
-            })
-        });
-
-        // send latest command when there's no active query
-        if (!(*query_sent)) && (!next_command.is_empty()) {
-            unsafe {
-                uart_serial.lock(|s| s.write_buf_flush(next_command.as_mut_vec()))?;
+        // no answer, drop the query (and whatever came of a response), next ping retries
+        if !self.uart_eol && self.ps.link.query_timed_out(now) {
+            self.query_sent = false;
+            self.query.lock(|qopt| qopt.take());
+            self.uart_line_buf.clear();
+        }
+        self.ps.check_link(now)?;
+
+        let uart_serial = &mut self.uart_serial;
+        let uart_eol = self.uart_eol;
+        let query_sent = &mut self.query_sent;
+
+        // send queued commands when there's no active query, hold them while the link is down
+        // (setpoints over the soft limits are dropped, reported once polling is done)
+        let mut limits_ok = Ok(());
+        if (!(*query_sent)) && (!self.ps.commands.is_empty()) && (!self.ps.link.is_lost()) {
+            limits_ok = self.ps.check_commands();
+            let mut cmdbuf: String<U256> = String::new();
+            self.ps.commands.drain_to_str(&mut cmdbuf)?;
+            if !cmdbuf.is_empty() {
+                uart_serial.lock(|s| s.write_buf_flush(cmdbuf.as_bytes()))?;
+
+                ifcfg!("bin_debug", hprintln!("sent {}", cmdbuf));
+                asm::delay(COMMAND_DELAY_MS * SYS_CYCLES_PER_MILLISECOND);
             }
-
-            ifcfg!("bin_debug", hprintln!("sent {}", next_command));
-            next_command.clear();
-            asm::delay(SYS_FREQ.0 / 100);
         }
 
-        let q = query.lock(|qopt| match qopt {
+        let link = &mut self.ps.link;
+        let q = self.query.lock(|qopt| match qopt {
             None => Ok::<Option<Query>, AppError>(None),
             Some(q) => {
                 if !(*query_sent) {
@@ -658,10 +1171,12 @@ impl<'a> IdleLoop<'a> {
                     q.write_serial_cmd_buf(&mut sbuf);
                     uart_serial.lock(|s| s.write_buf_flush(&sbuf.into_bytes()))?;
                     *query_sent = true;
+                    link.query_sent(now);
                 }
 
-                if *uart_eol {
+                if uart_eol {
                     *query_sent = false;
+                    link.query_done(now);
                     Ok(qopt.take())
                 } else {
                     Ok(None)
@@ -673,27 +1188,61 @@ impl<'a> IdleLoop<'a> {
             None => {}
             Some(q) => {
                 let mut sbuf: String<U64> = String::new();
-                to_str_skip_whitespace(uart_line_buf, &mut sbuf)?;
-                uart_line_buf.clear();
+                to_str_skip_whitespace(&self.uart_line_buf, &mut sbuf)?;
+                self.uart_line_buf.clear();
                 ifcfg!("bin_debug", hprintln!("qres {:?} {}", q, sbuf));
 
-                is.set_query_result(&q, &sbuf)?;
+                self.ps.set_query_result(&q, &sbuf)?;
+                self.ps.link.response_ok();
 
                 // send query/response to USB host
                 let mut buf: String<U64> = String::new();
                 buf.push_str(&q.to_str()).map_err(|_| AppError::Duh)?;
                 write!(buf, "\t{}\r\n", sbuf).map_err(|_| AppError::Duh)?;
-                usb_serial.lock(|s| s.write(&buf.into_bytes()))?;
+                self.usb_serial.lock(|s| s.write(&buf.into_bytes()))?;
             }
         }
 
-        is.handle_rotary_encoder(
+        limits_ok
+    }
+
+    #[inline]
+    fn handle_state_live_screen(
+        &mut self,
+        encoder_change: i16,
+        button_press: Option<MilliSeconds>,
+    ) -> Result<(), AppError> {
+        let (encoder_press, btn_encoder_is_pressed) = self.btn_encoder.lock(|b| {
+            if encoder_change != 0 {
+                b.cancel_last_press()
+            }
+            (
+                b.take_last_press(time::MilliSeconds(60)),
+                b.is_pressed(time::MilliSeconds(30)),
+            )
+        });
+
+        ifcfg!("bin_info", {
+            (match encoder_press {
+                None => Ok(()),
+                Some(e) => hprintln!("BTN E {}", e.0),
+            })
+            .and_then(|_| {
+                if encoder_change != 0 {
+                    hprintln!("RE {}", encoder_change)
+                } else {
+                    Ok(())
+                }
+            })
+        });
+
+        let now = self.clock.now();
+        self.ps.handle_input(
+            now,
+            encoder_change,
             encoder_press,
             btn_encoder_is_pressed,
-            encoder_change,
-            next_command,
-        )?;
-
-        Ok(())
+            button_press,
+        )
     }
 }
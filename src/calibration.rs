@@ -0,0 +1,252 @@
+//! User calibration of VOUT/IOUT readbacks (gain and offset per channel),
+//! two point guided calibration against a reference meter
+
+use core::fmt::Write;
+
+use heapless::{ArrayLength, String};
+use num_traits::float::FloatCore;
+
+use stm32f4xx_hal::time::MilliSeconds;
+
+use crate::{
+    model::{PSChannel, VarSelected},
+    prelude::*,
+    protocol::Channel,
+};
+
+/// Saved calibration, loaded on startup (same format as `@cal` directives)
+pub const CAL_FILE: &str = "CALIB";
+
+/// Anything further off than this is a mistake rather than calibration
+const MIN_GAIN: f32 = 0.5;
+const MAX_GAIN: f32 = 2.0;
+
+/// corrected = raw * gain + offset
+#[derive(Copy, Clone)]
+pub struct Cal {
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl Cal {
+    pub const fn new() -> Self {
+        Cal {
+            gain: 1.0,
+            offset: 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn apply(&self, raw: f32) -> f32 {
+        raw * self.gain + self.offset
+    }
+
+    /// Back to what the instrument reported
+    #[inline]
+    pub fn raw(&self, corrected: f32) -> f32 {
+        (corrected - self.offset) / self.gain
+    }
+
+    /// Line through two (raw, reference) points, offset only when they're too close
+    pub fn from_points(p1: (f32, f32), p2: (f32, f32)) -> Option<Self> {
+        let ((r1, m1), (r2, m2)) = (p1, p2);
+        let cal = if (r2 - r1).abs() < 0.01 {
+            Cal {
+                gain: 1.0,
+                offset: (m1 + m2 - r1 - r2) / 2.0,
+            }
+        } else {
+            let gain = (m2 - m1) / (r2 - r1);
+            Cal {
+                gain,
+                offset: m1 - r1 * gain,
+            }
+        };
+
+        Some(cal).filter(Cal::is_valid)
+    }
+
+    /// Gain in range (no division by zero in `raw`), a finite offset
+    pub fn is_valid(&self) -> bool {
+        self.gain >= MIN_GAIN && self.gain <= MAX_GAIN && self.offset.is_finite()
+    }
+
+    /// As a project file directive
+    pub fn write_directive<S>(
+        &self,
+        ch: Channel,
+        target: VarSelected,
+        buf: &mut String<S>,
+    ) -> Result<(), AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        write!(
+            buf,
+            "@cal {} {} {:.6} {:.5}\r\n",
+            ch.to_str(),
+            target_str(target),
+            self.gain,
+            self.offset
+        )?;
+        Ok(())
+    }
+}
+
+/// VOUT and IOUT calibration of a channel
+#[derive(Copy, Clone)]
+pub struct ChannelCal {
+    pub vout: Cal,
+    pub iout: Cal,
+}
+
+impl ChannelCal {
+    pub const fn new() -> Self {
+        ChannelCal {
+            vout: Cal::new(),
+            iout: Cal::new(),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, target: VarSelected) -> &Cal {
+        match target {
+            VarSelected::V => &self.vout,
+            VarSelected::I => &self.iout,
+        }
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, target: VarSelected) -> &mut Cal {
+        match target {
+            VarSelected::V => &mut self.vout,
+            VarSelected::I => &mut self.iout,
+        }
+    }
+}
+
+/// In display order
+pub const CAL_TARGETS: [(Channel, VarSelected); 4] = [
+    (Channel::Ch1, VarSelected::V),
+    (Channel::Ch1, VarSelected::I),
+    (Channel::Ch2, VarSelected::V),
+    (Channel::Ch2, VarSelected::I),
+];
+
+#[inline]
+pub fn target_str(target: VarSelected) -> &'static str {
+    match target {
+        VarSelected::V => "v",
+        VarSelected::I => "i",
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum CalStep {
+    Select,
+    Point(usize),
+    Done,
+}
+
+/// Pick a reading, enter the reference meter value at two points
+pub struct CalScreen {
+    pub selected: usize, // in CAL_TARGETS
+    pub step: CalStep,
+    pub ref_val: f32,
+    pub points: [(f32, f32); 2], // (raw, reference)
+    pub result: Option<Cal>,     // None if the points didn't make sense
+    pub save_pending: bool,
+    pub saved: bool,
+}
+
+impl CalScreen {
+    pub fn new() -> Self {
+        CalScreen {
+            selected: 0,
+            step: CalStep::Select,
+            ref_val: 0.0,
+            points: [(0.0, 0.0); 2],
+            result: None,
+            save_pending: false,
+            saved: false,
+        }
+    }
+
+    #[inline]
+    pub fn target(&self) -> (Channel, VarSelected) {
+        CAL_TARGETS[self.selected]
+    }
+
+    /// Corrected reading of the selected target
+    #[inline]
+    pub fn reading(&self, psch: &PSChannel) -> Option<f32> {
+        match self.target().1 {
+            VarSelected::V => psch.vout,
+            VarSelected::I => psch.iout,
+        }
+    }
+
+    /// Press to move on, very long press resets the selected target (select step),
+    /// encoder sets the reference value (x100 while pressed)
+    pub fn handle_rotary_encoder(
+        &mut self,
+        psch: &mut PSChannel,
+        re_press_duration: Option<MilliSeconds>,
+        short_press: MilliSeconds,
+        re_pressed: bool,
+        re_diff: i16,
+    ) {
+        let press = re_press_duration.filter(|pd| pd > &short_press);
+        let target = self.target().1;
+
+        match self.step {
+            CalStep::Select => match press {
+                Some(pd) if pd > MilliSeconds(1000) => {
+                    *psch.cal.get_mut(target) = Cal::new();
+                    self.save_pending = true;
+                }
+                Some(_) => {
+                    self.ref_val = self.reading(psch).unwrap_or(0.0);
+                    self.step = CalStep::Point(0);
+                }
+                None => {
+                    self.selected = (self.selected as i16 + re_diff)
+                        .max(0)
+                        .min(CAL_TARGETS.len() as i16 - 1)
+                        as usize
+                }
+            },
+            CalStep::Point(n) => {
+                let step = if re_pressed { 0.1 } else { 0.001 };
+                self.ref_val = (self.ref_val + step * re_diff as f32).max(0.0);
+
+                match press.and(self.reading(psch)) {
+                    Some(v) => {
+                        let cal = psch.cal.get_mut(target);
+                        self.points[n] = (cal.raw(v), self.ref_val);
+                        if n == 0 {
+                            self.step = CalStep::Point(1);
+                        } else {
+                            self.result = Cal::from_points(self.points[0], self.points[1]);
+                            match self.result {
+                                Some(c) => {
+                                    *cal = c;
+                                    self.save_pending = true;
+                                }
+                                None => (),
+                            }
+                            self.saved = false;
+                            self.step = CalStep::Done;
+                        }
+                    }
+                    None => (),
+                }
+            }
+            CalStep::Done => {
+                if press.is_some() {
+                    self.step = CalStep::Select;
+                }
+            }
+        }
+    }
+}
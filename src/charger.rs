@@ -0,0 +1,275 @@
+//! CC/CV battery charger.
+//!
+//! Charges at a current limit up to the target voltage (CC),
+//! holds the voltage until the current drops below the termination current (CV).
+//! Stops on a timeout, a charge (Ah) limit, an instrument fault
+//! or when the output is switched off behind its back.
+
+use crate::{clock::Millis, model::PSChannel, prelude::*, protocol::*};
+
+/// Output is considered to be in CV when it's this close to the target (or reports CV status)
+const CV_DETECT_V: f32 = 0.02;
+
+/// Current has to stay below termination this long (readings are noisy)
+const TERM_HOLD_MS: u64 = 5_000;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Chemistry {
+    LiIon,
+    LiFePO4,
+    LeadAcid,
+}
+
+impl Chemistry {
+    pub fn parse(s: &str) -> Result<Self, AppError> {
+        match s {
+            "liion" => Ok(Chemistry::LiIon),
+            "lifepo4" => Ok(Chemistry::LiFePO4),
+            "pb" => Ok(Chemistry::LeadAcid),
+            _ => Err(AppError::ProjectFileError),
+        }
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Chemistry::LiIon => "liion",
+            Chemistry::LiFePO4 => "lifepo4",
+            Chemistry::LeadAcid => "pb",
+        }
+    }
+
+    /// Charge voltage per cell
+    pub fn cell_v(&self) -> f32 {
+        match self {
+            Chemistry::LiIon => 4.2,
+            Chemistry::LiFePO4 => 3.65,
+            Chemistry::LeadAcid => 2.45,
+        }
+    }
+}
+
+/// Charge settings, from a project file
+#[derive(Copy, Clone)]
+pub struct ChargeProfile {
+    pub chemistry: Chemistry,
+    pub cells: u8,
+    pub i_charge: f32,
+    pub i_term: f32,
+    pub max_ah: f32, // 0 is no limit
+    pub timeout_ms: u64,
+    pub cv_timeout_ms: u64,
+}
+
+impl ChargeProfile {
+    pub fn new(chemistry: Chemistry, cells: u8, i_charge: f32) -> Self {
+        ChargeProfile {
+            chemistry,
+            cells,
+            i_charge,
+            i_term: i_charge / 10.0,
+            max_ah: 0.0,
+            timeout_ms: 10 * 3600 * 1000,
+            cv_timeout_ms: 3 * 3600 * 1000,
+        }
+    }
+
+    #[inline]
+    pub fn v_target(&self) -> f32 {
+        self.chemistry.cell_v() * self.cells as f32
+    }
+}
+
+/// Why charging stopped
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ChargeEnd {
+    Complete,
+    Timeout,
+    AhLimit,
+    Fault,
+    OutputOff,
+    Aborted,
+}
+
+impl ChargeEnd {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            ChargeEnd::Complete => "complete",
+            ChargeEnd::Timeout => "timeout",
+            ChargeEnd::AhLimit => "Ah limit",
+            ChargeEnd::Fault => "fault",
+            ChargeEnd::OutputOff => "output off",
+            ChargeEnd::Aborted => "aborted",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ChargePhase {
+    Ready, // loaded, waiting for a start
+    CC,
+    CV,
+    Done(ChargeEnd),
+}
+
+impl ChargePhase {
+    #[inline]
+    pub fn is_charging(&self) -> bool {
+        *self == ChargePhase::CC || *self == ChargePhase::CV
+    }
+}
+
+pub struct Charger {
+    pub ch: Channel,
+    pub profile: ChargeProfile,
+    pub phase: ChargePhase,
+    pub elapsed_ms: u64,
+    pub ah: f32,
+    pub wh: f32,
+    pub final_v: Option<f32>,
+    last_tick: Option<Millis>,
+    cv_started: Millis,
+    term_since: Option<Millis>,
+    output_seen_on: bool,
+}
+
+impl Charger {
+    pub fn new(ch: Channel, profile: ChargeProfile) -> Self {
+        Charger {
+            ch,
+            profile,
+            phase: ChargePhase::Ready,
+            elapsed_ms: 0,
+            ah: 0.0,
+            wh: 0.0,
+            final_v: None,
+            last_tick: None,
+            cv_started: 0,
+            term_since: None,
+            output_seen_on: false,
+        }
+    }
+
+    /// Set the limits and switch the output on
+    pub fn start(&mut self, psch: &mut PSChannel, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        if self.phase != ChargePhase::Ready {
+            return Ok(());
+        }
+
+        psch.ramp = None;
+        cmds.push(Command::Vset {
+            ch: self.ch,
+            val: self.profile.v_target(),
+        })?;
+        cmds.push(Command::Iset {
+            ch: self.ch,
+            val: self.profile.i_charge,
+        })?;
+        cmds.push(Command::Out {
+            ch: self.ch,
+            on: true,
+        })?;
+        psch.out = None; // wait for the next poll
+
+        self.phase = ChargePhase::CC;
+        Ok(())
+    }
+
+    /// Stop charging, switch the output off
+    pub fn stop(
+        &mut self,
+        psch: &mut PSChannel,
+        cmds: &mut CommandQueue,
+        reason: ChargeEnd,
+    ) -> Result<(), AppError> {
+        if !self.phase.is_charging() {
+            return Ok(());
+        }
+
+        cmds.push(Command::Out {
+            ch: self.ch,
+            on: false,
+        })?;
+        psch.out = None;
+
+        self.final_v = psch.vout;
+        self.phase = ChargePhase::Done(reason);
+        Ok(())
+    }
+
+    /// Periodic update, integrates charge and moves through the phases
+    pub fn tick(
+        &mut self,
+        now: Millis,
+        psch: &mut PSChannel,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        let dt = now.saturating_sub(*self.last_tick.get_or_insert(now));
+        self.last_tick = Some(now);
+
+        if !self.phase.is_charging() {
+            return Ok(());
+        }
+
+        self.elapsed_ms += dt;
+
+        match psch.out {
+            Some(true) => {
+                self.output_seen_on = true;
+                match psch.vout.zip(psch.iout) {
+                    Some((v, i)) => {
+                        let h = dt as f32 / 3_600_000.0;
+                        self.ah += i.max(0.0) * h;
+                        self.wh += v.max(0.0) * i.max(0.0) * h;
+                    }
+                    None => (),
+                }
+            }
+            Some(false) if self.output_seen_on => {
+                return self.stop(psch, cmds, ChargeEnd::OutputOff);
+            }
+            _ => (),
+        }
+
+        let sts = psch.active_status();
+        if sts.map_or(false, |st| st.is_ov() || st.is_oc() || st.is_ot()) {
+            return self.stop(psch, cmds, ChargeEnd::Fault);
+        }
+
+        if self.elapsed_ms > self.profile.timeout_ms {
+            return self.stop(psch, cmds, ChargeEnd::Timeout);
+        }
+
+        if self.profile.max_ah > 0.0 && self.ah >= self.profile.max_ah {
+            return self.stop(psch, cmds, ChargeEnd::AhLimit);
+        }
+
+        match self.phase {
+            ChargePhase::CC => {
+                let at_target = psch
+                    .vout
+                    .map_or(false, |v| v >= self.profile.v_target() - CV_DETECT_V);
+                if at_target || sts.map_or(false, |st| st.is_cv()) {
+                    self.phase = ChargePhase::CV;
+                    self.cv_started = now;
+                }
+            }
+            ChargePhase::CV => {
+                if psch.iout.map_or(false, |i| i < self.profile.i_term) {
+                    let since = *self.term_since.get_or_insert(now);
+                    if now - since >= TERM_HOLD_MS {
+                        return self.stop(psch, cmds, ChargeEnd::Complete);
+                    }
+                } else {
+                    self.term_since = None;
+                }
+
+                if now - self.cv_started > self.profile.cv_timeout_ms {
+                    return self.stop(psch, cmds, ChargeEnd::Timeout);
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+//! Monotonic time base
+
+use cortex_m::peripheral::DWT;
+
+use crate::consts::SYS_CYCLES_PER_MILLISECOND;
+
+/// Milliseconds since boot
+pub type Millis = u64;
+
+/// Extends 32 bit CYCCNT (wraps every ~44s at 96MHz) into a 64 bit counter.
+/// Has to be polled at least once per CYCCNT period to notice every wrap.
+pub struct Clock {
+    last_cycles: u32,
+    wraps: u32,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock {
+            last_cycles: DWT::get_cycle_count(),
+            wraps: 0,
+        }
+    }
+
+    pub fn now(&mut self) -> Millis {
+        let cycles = DWT::get_cycle_count();
+        if cycles < self.last_cycles {
+            self.wraps += 1;
+        }
+        self.last_cycles = cycles;
+
+        (((self.wraps as u64) << 32) | cycles as u64) / SYS_CYCLES_PER_MILLISECOND as u64
+    }
+}
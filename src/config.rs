@@ -0,0 +1,136 @@
+//! Controller configuration, read from `CONFIG` (SD card root) at startup.
+//!
+//! ```text
+//! # GPIB address of the instrument, sent to the serial adapter before BOOT
+//! gpib_addr 5
+//! poll_hz 16
+//! baud 115200
+//! encoder_steps 4
+//! short_press_ms 100
+//! select_press_ms 200
+//! long_press_ms 700
+//! ui_timeout_ms 3000
+//! # sets the RTC, only when it has lost the time (no backup battery)
+//! time 2026-10-18 12:00
+//! ```
+//!
+//! One `<key> <value>` per line, `#` starts a comment. Missing keys (or no file
+//! at all) keep the defaults above, so does a line that doesn't parse.
+
+use core::str::from_utf8;
+
+use stm32f4xx_hal::time::MilliSeconds;
+
+use crate::{
+    directive::{next_arg_str, parse_arg},
+    prelude::*,
+    sdcard::SDCard,
+    time::DateTime,
+};
+
+pub const CONFIG_FILE: &str = "CONFIG";
+
+#[derive(Copy, Clone)]
+pub struct Config {
+    pub gpib_addr: u8,
+    pub poll_hz: u32,               // channel readings
+    pub baud: u32,                  // serial adapter
+    pub encoder_steps: i16,         // encoder counts per detent
+    pub short_press: MilliSeconds,  // shorter presses are ignored
+    pub select_press: MilliSeconds, // longer encoder presses pick the variable (or graph span)
+    pub long_press: MilliSeconds,   // longer button presses open the file selector
+    pub ui_timeout_ms: u64,         // dialed in setpoints are shown this long after a change
+    pub time: Option<DateTime>,     // for an RTC that isn't set
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Config {
+            gpib_addr: 5,
+            poll_hz: QUERY_PING_HZ,
+            baud: 115_200,
+            encoder_steps: 4,
+            short_press: MilliSeconds(100),
+            select_press: MilliSeconds(200),
+            long_press: MilliSeconds(700),
+            ui_timeout_ms: 3000,
+            time: None,
+        }
+    }
+
+    /// Defaults updated from the config file, with the first line that didn't parse
+    pub fn load(sdc: &mut SDCard) -> (Self, Option<u16>) {
+        let mut config = Config::new();
+        let mut line_no = 0u16;
+        let mut bad_line = None;
+
+        let res = sdc.read_lines(CONFIG_FILE, |line| {
+            line_no = line_no.saturating_add(1);
+            if config.parse_line(line).is_err() && bad_line.is_none() {
+                bad_line = Some(line_no);
+            }
+            Ok(())
+        });
+
+        // no file is fine, a line that can't be read isn't
+        if res.is_err() && line_no > 0 && bad_line.is_none() {
+            bad_line = Some(line_no.saturating_add(1));
+        }
+
+        (config, bad_line)
+    }
+
+    /// Set a key, blank lines and comments are skipped
+    pub fn parse_line(&mut self, line: &[u8]) -> Result<(), AppError> {
+        let line = from_utf8(line).map_err(|_| AppError::ProjectFileError)?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let mut args = line.split_whitespace();
+        let key = next_arg_str(&mut args)?;
+        let val = next_arg_str(&mut args)?;
+
+        match key {
+            "gpib_addr" => self.gpib_addr = parse_in(val, 1, 30)?,
+            "poll_hz" => self.poll_hz = parse_in(val, 1, 50)?,
+            "baud" => self.baud = parse_in(val, 1200, 921_600)?,
+            "encoder_steps" => self.encoder_steps = parse_in(val, 1, 16)?,
+            "short_press_ms" => self.short_press = MilliSeconds(parse_in(val, 10, 1000)?),
+            "select_press_ms" => self.select_press = MilliSeconds(parse_in(val, 10, 2000)?),
+            "long_press_ms" => self.long_press = MilliSeconds(parse_in(val, 100, 5000)?),
+            "ui_timeout_ms" => self.ui_timeout_ms = parse_in(val, 500, 60_000)?,
+            "time" => {
+                let time = next_arg_str(&mut args)?;
+                self.time =
+                    Some(DateTime::parse(val, time).map_err(|_| AppError::ProjectFileError)?);
+            }
+            _ => return Err(AppError::ProjectFileError),
+        }
+
+        if args.next().is_some() {
+            Err(AppError::ProjectFileError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Time between channel reading queries
+    #[inline]
+    pub fn ping_ms(&self) -> u32 {
+        1000 / self.poll_hz
+    }
+}
+
+fn parse_in<T>(s: &str, min: T, max: T) -> Result<T, AppError>
+where
+    T: core::str::FromStr + PartialOrd,
+{
+    let v: T = parse_arg(s)?;
+    if v < min || v > max {
+        Err(AppError::ProjectFileError)
+    } else {
+        Ok(v)
+    }
+}
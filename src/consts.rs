@@ -2,3 +2,15 @@ use stm32f4xx_hal::time::Hertz;
 
 pub const SYS_FREQ: Hertz = Hertz(96_000_000);
 pub const SYS_CYCLES_PER_MILLISECOND: u32 = SYS_FREQ.0 / 1000;
+
+/// Channel readings are polled at this rate (when the link keeps up), unless configured
+pub const QUERY_PING_HZ: u32 = 16;
+
+/// Pause after sending commands, gives the instrument time to parse them
+pub const COMMAND_DELAY_MS: u32 = 10;
+
+/// Unanswered query is dropped after this long (and sent again)
+pub const QUERY_TIMEOUT_MS: u32 = 500;
+
+/// The SD card is checked for at this interval (removal or insertion)
+pub const CARD_CHECK_MS: u64 = 2000;
@@ -0,0 +1,202 @@
+//! CSV data logging to the SD card.
+//!
+//! A row per interval with readings of both channels, rows are buffered
+//! and appended to the log file in batches so the card isn't written
+//! to between every pair of queries.
+//!
+//! ```text
+//! time,t_s,vout1,iout1,pout1,sts1,vout2,iout2,pout2,sts2
+//! 2026-10-18 12:00:00,0.0,5.002,0.120,0.600,1,0.000,0.000,0.000,0
+//! ```
+//!
+//! Readings that aren't known (yet) are left empty, so is the time
+//! when the RTC isn't set. Rows are kept while there's no card (or it
+//! can't be written), the file is picked once there's one.
+
+use core::fmt::Write;
+
+use heapless::{consts::*, String};
+
+use crate::{clock::Millis, model::PSChannel, prelude::*, time::DateTime};
+
+/// New log files are "LOGnnn.CSV"
+pub const LOG_FILE_PREFIX: &str = "LOG";
+pub const LOG_FILE_EXT: &str = "CSV";
+
+/// Buffered rows are written once there's this much
+const FLUSH_BYTES: usize = 512;
+
+/// ... or the oldest of them is this old, also the retry interval after a failed write
+const FLUSH_MS: u64 = 10_000;
+
+const HEADER: &str = "time,t_s,vout1,iout1,pout1,sts1,vout2,iout2,pout2,sts2\r\n";
+
+pub struct DataLog {
+    pub interval_ms: u32,
+    pub running: bool,
+    pub started: bool,              // a run is on, until its last rows are written
+    pub fname: Option<String<U16>>, // file of the current run, picked on the first write
+    pub rows: u32,                  // rows so far
+    pub dropped: u32,               // rows that didn't fit in the buffer
+    pub failing: bool,              // last write failed, rows are kept until the next try
+    buf: String<U1024>,
+    start: Millis,
+    next_row: Millis,
+    oldest: Option<Millis>, // first row that hasn't been written
+    retry_at: Millis,
+}
+
+impl DataLog {
+    pub fn new() -> Self {
+        DataLog {
+            interval_ms: 1000,
+            running: false,
+            started: false,
+            fname: None,
+            rows: 0,
+            dropped: 0,
+            failing: false,
+            buf: String::new(),
+            start: 0,
+            next_row: 0,
+            oldest: None,
+            retry_at: 0,
+        }
+    }
+
+    /// Switch it on, a new run starts on the next tick (rows of a stopped
+    /// one that weren't written yet are dropped)
+    pub fn run(&mut self) {
+        if !self.running {
+            self.running = true;
+            self.started = false;
+        }
+    }
+
+    /// New run (into a new file), starts with the header
+    fn start(&mut self, now: Millis) -> Result<(), AppError> {
+        self.started = true;
+        self.fname = None;
+        self.rows = 0;
+        self.dropped = 0;
+        self.failing = false;
+        self.start = now;
+        self.next_row = now;
+        self.retry_at = now;
+        self.buf.clear();
+        self.buf.push_str(HEADER).map_err(|_| AppError::Duh)?;
+        self.oldest = Some(now);
+        Ok(())
+    }
+
+    /// No more rows, what's buffered is still written
+    pub fn stop(&mut self) {
+        self.running = false;
+        if self.buf.is_empty() {
+            self.started = false;
+        }
+    }
+
+    /// Start a run once it's switched on, add a row when it's due
+    pub fn tick(
+        &mut self,
+        now: Millis,
+        wall_time: Option<&DateTime>,
+        ch1: &PSChannel,
+        ch2: &PSChannel,
+    ) {
+        if self.running && !self.started && self.start(now).is_err() {
+            return;
+        }
+        if !self.running || now < self.next_row {
+            return;
+        }
+        self.next_row += self.interval_ms.max(1) as u64;
+        if self.next_row <= now {
+            self.next_row = now + self.interval_ms.max(1) as u64; // fell behind, skip ahead
+        }
+
+        let mut row: String<U128> = String::new();
+        let res = match wall_time {
+            Some(dt) => write!(row, "{},", dt),
+            None => row.push(',').map_err(|_| core::fmt::Error),
+        }
+        .and_then(|_| write!(row, "{:.1}", (now - self.start) as f32 / 1000.0))
+        .and_then(|_| write_channel(&mut row, ch1))
+        .and_then(|_| write_channel(&mut row, ch2))
+        .and_then(|_| row.push_str("\r\n").map_err(|_| core::fmt::Error));
+
+        if res.is_err() || self.buf.push_str(&row).is_err() {
+            self.dropped = self.dropped.saturating_add(1);
+        } else {
+            self.rows = self.rows.saturating_add(1);
+            self.oldest.get_or_insert(now);
+        }
+    }
+
+    /// Time to write the buffered rows
+    pub fn flush_due(&self, now: Millis) -> bool {
+        !self.buf.is_empty()
+            && now >= self.retry_at
+            && (!self.running
+                || self.buf.len() >= FLUSH_BYTES
+                || self.oldest.map_or(false, |t| now - t >= FLUSH_MS))
+    }
+
+    /// Rows to write
+    #[inline]
+    pub fn pending(&self) -> &[u8] {
+        self.buf.as_bytes()
+    }
+
+    /// Buffered rows were written, the run is over once it's stopped
+    pub fn flushed(&mut self) {
+        self.buf.clear();
+        self.oldest = None;
+        self.failing = false;
+        if !self.running {
+            self.started = false;
+        }
+    }
+
+    /// The file went with the card, the rest of the run goes to a new one
+    /// (with its own header)
+    pub fn card_removed(&mut self) {
+        if self.fname.take().is_none() {
+            return;
+        }
+
+        let mut buf: String<U1024> = String::new();
+        buf.push_str(HEADER).ok();
+        if buf.push_str(&self.buf).is_err() {
+            let rows = self.buf.matches('\n').count() as u32;
+            self.dropped = self.dropped.saturating_add(rows);
+        }
+        self.buf = buf;
+    }
+
+    /// Couldn't write, rows are kept for another try later,
+    /// the error is passed on the first time only
+    pub fn write_failed(&mut self, now: Millis, e: AppError) -> Result<(), AppError> {
+        self.retry_at = now + FLUSH_MS;
+        if self.failing {
+            Ok(())
+        } else {
+            self.failing = true;
+            Err(e)
+        }
+    }
+}
+
+fn write_channel(row: &mut String<U128>, ch: &PSChannel) -> core::fmt::Result {
+    for v in [ch.vout, ch.iout, ch.pout()].iter() {
+        match v {
+            Some(v) => write!(row, ",{:.3}", v)?,
+            None => row.push(',').map_err(|_| core::fmt::Error)?,
+        }
+    }
+    match ch.sts {
+        Some(sts) => write!(row, ",{}", sts.0),
+        None => row.push(',').map_err(|_| core::fmt::Error),
+    }
+}
@@ -0,0 +1,299 @@
+//! Controller directives in project files.
+//!
+//! Lines starting with '@' configure the controller itself,
+//! everything else is sent to the instrument as is.
+//!
+//! ```text
+//! @softstart <ch> <ramp seconds> [start volts]
+//! @softstart <ch> off
+//! @fuse <ch> <trip amps> <delay ms> [trip watts]
+//! @fuse <ch> off
+//! @limit <ch> <max volts> <max amps>
+//! @cal <ch> <v|i> <gain> <offset>
+//! @autooff <ch|all> <minutes>
+//! @autooff <ch|all> off
+//! @watchdog <timeout seconds> <keep|off>
+//! @log <interval seconds>
+//! @log off
+//! @time <YYYY-MM-DD> <HH:MM[:SS]>
+//! @files <ext> ...
+//! @files all
+//! @sequence [cycles]
+//! @script
+//! @waveform <ch> <v|i> <shape> <period seconds> <amplitude> <offset> [update ms]
+//! @table <value> ...
+//! @charge <ch> <liion|lifepo4|pb> <cells> <charge amps> [termination amps] [max Ah] [timeout minutes]
+//! @sweep <ch> <v|i> <start> <stop> <points> <dwell seconds> <limit>
+//! ```
+//!
+//! Lines after `@sequence` are sequencer steps, see `sequencer`,
+//! lines after `@script` are a script, see `script`.
+//! `@table` values (-1 to 1, scaled by amplitude) are appended to the
+//! table of the last `@waveform`, shapes are sine, square, triangle, saw and table.
+//! `@charge` loads a charger profile, charging starts from the charger screen.
+//! `@sweep` steps VSET (or ISET) with the other setpoint at `limit`,
+//! it starts from the run row of the sweep screen.
+//! `@time` sets the real-time clock.
+
+use core::str::{from_utf8, FromStr, SplitWhitespace};
+
+use heapless::{consts::*, String, Vec};
+
+use crate::{
+    calibration::Cal,
+    charger::*,
+    efuse::EFuse,
+    limits::*,
+    model::{ChSelected, VarSelected},
+    prelude::*,
+    protocol::Channel,
+    softstart::SoftStart,
+    sweep::{SweepParams, SWEEP_MAX_POINTS},
+    time::DateTime,
+    waveform::*,
+};
+
+pub enum Directive {
+    SoftStart { ch: Channel, softstart: SoftStart },
+    EFuse { ch: Channel, efuse: EFuse },
+    Limit { ch: Channel, limits: SoftLimits },
+    Calibration(Channel, VarSelected, Cal),
+    AutoOff { chs: ChSelected, ms: Option<u64> }, // no duration is off
+    Watchdog { timeout_ms: u32, safe_off: bool },
+    Log(Option<u32>), // row interval, no interval stops logging
+    Time(DateTime),
+    Files(Vec<String<U3>, U4>), // extensions the file selector lists, all if empty
+    Sequence { cycles: u16 },
+    Script,
+    Waveform(Waveform),
+    Table(Vec<f32, U16>),
+    Charge { ch: Channel, profile: ChargeProfile },
+    Sweep(SweepParams),
+}
+
+impl Directive {
+    /// None if it's an instrument command
+    pub fn parse(line: &[u8]) -> Result<Option<Self>, AppError> {
+        if line.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'@') {
+            return Ok(None);
+        }
+
+        let line = from_utf8(line).map_err(|_| AppError::ProjectFileError)?;
+        let mut args = line.trim()[1..].split_whitespace();
+
+        match args.next() {
+            Some("softstart") => {
+                let ch = Channel::parse(next_arg_str(&mut args)?)?;
+                let mut softstart = SoftStart::new();
+                match args.next() {
+                    Some("off") => (),
+                    Some(ramp_s) => {
+                        softstart.enabled = true;
+                        softstart.ramp_ms = (parse_arg::<f32>(ramp_s)? * 1000.0) as u32;
+                        softstart.start_v = args.next().map(parse_arg).unwrap_or(Ok(0.0))?;
+                        if !(softstart.start_v >= 0.0 && softstart.start_v <= V_MAX) {
+                            return Err(AppError::ProjectFileError); // NaN too
+                        }
+                    }
+                    None => return Err(AppError::ProjectFileError),
+                }
+                Ok(Some(Directive::SoftStart { ch, softstart }))
+            }
+            Some("fuse") => {
+                let ch = Channel::parse(next_arg_str(&mut args)?)?;
+                let mut efuse = EFuse::new();
+                match args.next() {
+                    Some("off") => (),
+                    Some(trip_i) => {
+                        efuse.enabled = true;
+                        efuse.trip_i = parse_arg(trip_i)?;
+                        efuse.delay_ms = parse_arg(next_arg_str(&mut args)?)?;
+                        efuse.trip_w = args.next().map(parse_arg).unwrap_or(Ok(0.0))?;
+                    }
+                    None => return Err(AppError::ProjectFileError),
+                }
+                Ok(Some(Directive::EFuse { ch, efuse }))
+            }
+            Some("limit") => {
+                let ch = Channel::parse(next_arg_str(&mut args)?)?;
+                let limits = SoftLimits {
+                    v_max: parse_arg(next_arg_str(&mut args)?)?,
+                    i_max: parse_arg(next_arg_str(&mut args)?)?,
+                };
+                if !limits.is_valid() {
+                    return Err(AppError::ProjectFileError);
+                }
+                Ok(Some(Directive::Limit { ch, limits }))
+            }
+            Some("cal") => {
+                let ch = Channel::parse(next_arg_str(&mut args)?)?;
+                let target = parse_target(next_arg_str(&mut args)?)?;
+                let cal = Cal {
+                    gain: parse_arg(next_arg_str(&mut args)?)?,
+                    offset: parse_arg(next_arg_str(&mut args)?)?,
+                };
+                if !cal.is_valid() {
+                    return Err(AppError::ProjectFileError);
+                }
+                Ok(Some(Directive::Calibration(ch, target, cal)))
+            }
+            Some("autooff") => {
+                let chs = match next_arg_str(&mut args)? {
+                    "all" => ChSelected::Both,
+                    c => match Channel::parse(c)? {
+                        Channel::Ch1 => ChSelected::Ch1,
+                        Channel::Ch2 => ChSelected::Ch2,
+                    },
+                };
+                let ms = match next_arg_str(&mut args)? {
+                    "off" => None,
+                    m => Some((parse_arg::<f32>(m)? * 60_000.0) as u64),
+                };
+                Ok(Some(Directive::AutoOff { chs, ms }))
+            }
+            Some("watchdog") => {
+                let timeout_s: f32 = parse_arg(next_arg_str(&mut args)?)?;
+                // same range as the settings screen, shorter than a query round trip would
+                // report the link lost on every query
+                if !(timeout_s >= 1.0 && timeout_s <= 60.0) {
+                    return Err(AppError::ProjectFileError);
+                }
+                let timeout_ms = (timeout_s * 1000.0) as u32;
+                let safe_off = match next_arg_str(&mut args)? {
+                    "keep" => false,
+                    "off" => true,
+                    _ => return Err(AppError::ProjectFileError),
+                };
+                Ok(Some(Directive::Watchdog {
+                    timeout_ms,
+                    safe_off,
+                }))
+            }
+            Some("log") => match next_arg_str(&mut args)? {
+                "off" => Ok(Some(Directive::Log(None))),
+                s => {
+                    let interval_s: f32 = parse_arg(s)?;
+                    if interval_s < 0.5 || interval_s > 3600.0 {
+                        return Err(AppError::ProjectFileError);
+                    }
+                    Ok(Some(Directive::Log(Some((interval_s * 1000.0) as u32))))
+                }
+            },
+            Some("time") => {
+                let date = next_arg_str(&mut args)?;
+                let time = next_arg_str(&mut args)?;
+                DateTime::parse(date, time)
+                    .map(|dt| Some(Directive::Time(dt)))
+                    .map_err(|_| AppError::ProjectFileError)
+            }
+            Some("files") => {
+                let mut exts = Vec::new();
+                for a in args {
+                    if a == "all" {
+                        exts.clear();
+                        break;
+                    }
+                    let valid = a.len() <= 3
+                        && !a.is_empty()
+                        && a.chars().all(|c| c.is_ascii_alphanumeric());
+                    if !valid {
+                        return Err(AppError::ProjectFileError);
+                    }
+                    let mut ext = String::new();
+                    for c in a.chars() {
+                        ext.push(c.to_ascii_uppercase())
+                            .map_err(|_| AppError::ProjectFileError)?;
+                    }
+                    exts.push(ext).map_err(|_| AppError::ProjectFileError)?;
+                }
+                Ok(Some(Directive::Files(exts)))
+            }
+            Some("sequence") => {
+                let cycles = args.next().map(parse_arg).unwrap_or(Ok(1))?;
+                Ok(Some(Directive::Sequence { cycles }))
+            }
+            Some("script") => Ok(Some(Directive::Script)),
+            Some("waveform") => {
+                let mut wf = Waveform::new();
+                wf.ch = Channel::parse(next_arg_str(&mut args)?)?;
+                wf.target = parse_target(next_arg_str(&mut args)?)?;
+                wf.shape = Shape::parse(next_arg_str(&mut args)?)?;
+                wf.period_ms = (parse_arg::<f32>(next_arg_str(&mut args)?)? * 1000.0) as u32;
+                wf.amplitude = parse_arg(next_arg_str(&mut args)?)?;
+                wf.offset = parse_arg(next_arg_str(&mut args)?)?;
+                wf.update_ms = args.next().map(parse_arg).unwrap_or(Ok(wf.update_ms))?;
+                wf.running = true;
+                Ok(Some(Directive::Waveform(wf)))
+            }
+            Some("table") => {
+                let mut vals = Vec::new();
+                for a in args {
+                    vals.push(parse_arg(a)?)
+                        .map_err(|_| AppError::ProjectFileError)?;
+                }
+                Ok(Some(Directive::Table(vals)))
+            }
+            Some("charge") => {
+                let ch = Channel::parse(next_arg_str(&mut args)?)?;
+                let mut profile = ChargeProfile::new(
+                    Chemistry::parse(next_arg_str(&mut args)?)?,
+                    parse_arg(next_arg_str(&mut args)?)?,
+                    parse_arg(next_arg_str(&mut args)?)?,
+                );
+                profile.i_term = args.next().map(parse_arg).unwrap_or(Ok(profile.i_term))?;
+                profile.max_ah = args.next().map(parse_arg).unwrap_or(Ok(profile.max_ah))?;
+                match args.next() {
+                    Some(m) => {
+                        profile.timeout_ms = parse_arg::<u64>(m)?
+                            .checked_mul(60_000)
+                            .ok_or(AppError::ProjectFileError)?
+                    }
+                    None => (),
+                }
+                if profile.cells == 0 || profile.i_term <= 0.0 {
+                    return Err(AppError::ProjectFileError);
+                }
+                Ok(Some(Directive::Charge { ch, profile }))
+            }
+            Some("sweep") => {
+                let mut sp = SweepParams::new();
+                sp.ch = Channel::parse(next_arg_str(&mut args)?)?;
+                sp.target = parse_target(next_arg_str(&mut args)?)?;
+                sp.start = parse_arg(next_arg_str(&mut args)?)?;
+                sp.stop = parse_arg(next_arg_str(&mut args)?)?;
+                sp.points = parse_arg(next_arg_str(&mut args)?)?;
+                sp.dwell_ms = (parse_arg::<f32>(next_arg_str(&mut args)?)? * 1000.0) as u32;
+                sp.limit = parse_arg(next_arg_str(&mut args)?)?;
+                if sp.points < 2 || sp.points > SWEEP_MAX_POINTS {
+                    return Err(AppError::ProjectFileError);
+                }
+                Ok(Some(Directive::Sweep(sp)))
+            }
+            _ => Err(AppError::ProjectFileError),
+        }
+    }
+}
+
+/// `@time` line, the USB host reading or setting the clock
+pub fn is_time_line(line: &[u8]) -> bool {
+    from_utf8(line).map_or(false, |l| l.split_whitespace().next() == Some("@time"))
+}
+
+#[inline]
+fn parse_target(s: &str) -> Result<VarSelected, AppError> {
+    match s {
+        "v" => Ok(VarSelected::V),
+        "i" => Ok(VarSelected::I),
+        _ => Err(AppError::ProjectFileError),
+    }
+}
+
+#[inline]
+pub(crate) fn next_arg_str<'l>(args: &mut SplitWhitespace<'l>) -> Result<&'l str, AppError> {
+    args.next().ok_or(AppError::ProjectFileError)
+}
+
+#[inline]
+pub(crate) fn parse_arg<T: FromStr>(s: &str) -> Result<T, AppError> {
+    s.parse::<T>().map_err(|_| AppError::ProjectFileError)
+}
@@ -2,21 +2,38 @@ use core::{convert::Infallible, fmt::Write};
 
 use embedded_hal::blocking::delay::DelayUs;
 
+use num_traits::float::FloatCore;
+
 use embedded_graphics::{
     egtext, fonts::*, pixelcolor::BinaryColor, prelude::*, primitives::*, style::*, text_style,
 };
 
 use stm32f4xx_hal::spi;
 
-use heapless::{consts::*, String};
+use heapless::{consts::*, ArrayLength, String};
 
-use crate::{delay::*, model::*, prelude::*};
+use crate::{
+    calibration::*, charger::*, delay::*, efuse::*, history::*, model::*, prelude::*, protocol::*,
+    script::*, sdcard::split_path, sequencer::*, session::*, settings::*, snapshot::*, stats::*,
+    sweep::*, waveform::*,
+};
 
 // 0 to n-1 based
 pub const WIDTH: i32 = 127;
 pub const HEIGHT: i32 = 63;
 
-const FILES_PER_SCREEN: usize = 8;
+const LINES_PER_SCREEN: usize = 8;
+
+// x offsets of min/max/avg columns on the stats screen
+const STATS_COLUMNS: [i32; 3] = [14, 52, 90];
+
+// menu screen status column (a date fits right of the longest item)
+const STATUS_X: i32 = WIDTH + 1 - 60;
+
+// history graph plot area, one column per history point, axis labels on the left
+const GRAPH_X: i32 = WIDTH + 1 - HISTORY_LEN as i32;
+const GRAPH_TOP: i32 = 8;
+const GRAPH_MIN_RANGE: f32 = 0.01;
 
 pub struct Display {
     device: DisplayDevice,
@@ -52,8 +69,8 @@ impl Display {
         self.clear()?;
 
         match &ps.error {
-            Some(e) => self.render_error(&e)?,
-            None => self.render_ui(&ps.ui)?,
+            Some(e) => self.render_error(&e, &ps.error_at)?,
+            None => self.render_ui(ps)?,
         }
 
         self.flush()?;
@@ -62,7 +79,11 @@ impl Display {
         Ok(())
     }
 
-    fn render_error(self: &mut Self, e: &AppError) -> Result<(), AppError> {
+    fn render_error(
+        self: &mut Self,
+        e: &AppError,
+        at: &Option<(String<U32>, u16)>,
+    ) -> Result<(), AppError> {
         let mut s: String<U32> = String::new();
         write!(&mut s, "{:?}", e)?;
 
@@ -73,19 +94,71 @@ impl Display {
         )
         .draw(&mut self.device)?;
 
+        match at {
+            Some((fname, line_no)) => {
+                s.clear();
+                write!(&mut s, "{} line {}", fname, line_no)?;
+                self.render_small_text(&s, 2, HEIGHT / 2 + 6)?;
+            }
+            None => (),
+        }
+
         Ok(())
     }
 
     #[inline]
-    fn render_ui(self: &mut Self, ps: &UI) -> Result<(), AppError> {
-        match ps {
+    fn render_ui(self: &mut Self, ps: &PS) -> Result<(), AppError> {
+        self.render_screen(ps)?;
+
+        // over whatever screen, the readings on it are stale
+        match &ps.ui {
+            UI::UILoading(_) | UI::USSBSerial => Ok(()),
+            _ if ps.link.is_lost() => self.render_link_lost(),
+            _ => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn render_screen(self: &mut Self, ps: &PS) -> Result<(), AppError> {
+        match &ps.ui {
             UI::UILoading(s) => self.render_ui_loading(s),
             UI::USSBSerial => self.render_usb_serial(),
-            UI::InfoScreen(is) => self.render_info_screen(is),
+            UI::InfoScreen(is) => self.render_info_screen(ps, is),
+            UI::MenuScreen(ms) => self.render_menu_screen(ps, ms),
+            UI::StatsScreen => self.render_stats_screen(ps),
+            UI::GraphScreen(gs) => self.render_graph_screen(ps, gs),
+            UI::SettingsScreen(ss) => self.render_settings_screen(ps, ss),
+            UI::SequencerScreen(sq) => self.render_sequencer_screen(ps, sq),
+            UI::ScriptScreen(sr) => self.render_script_screen(ps, sr),
+            UI::WaveformScreen(ws) => self.render_waveform_screen(ps, ws),
+            UI::ChargerScreen => self.render_charger_screen(ps),
+            UI::SweepScreen(ss) => self.render_sweep_screen(ps, ss),
+            UI::CalScreen(cs) => self.render_cal_screen(ps, cs),
+            UI::SaveScreen(ss) => self.render_save_screen(ps, ss),
+            UI::RestoreScreen(rs) => self.render_restore_screen(rs),
             UI::ProjectFiles(pfs) => self.render_project_files(pfs),
+            UI::FilePreview(fp, _) => self.render_file_preview(fp),
         }
     }
 
+    fn render_link_lost(self: &mut Self) -> Result<(), AppError> {
+        Rectangle::new(
+            Point::new(26, HEIGHT / 2 - 8),
+            Point::new(WIDTH - 26, HEIGHT / 2 + 8),
+        )
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(&mut self.device)?;
+
+        egtext!(
+            text = "LINK LOST",
+            top_left = Point::new(WIDTH / 2 - 26, HEIGHT / 2 - 3),
+            style = text_style!(font = Font6x8, text_color = BinaryColor::Off)
+        )
+        .draw(&mut self.device)?;
+
+        Ok(())
+    }
+
     #[inline]
     fn render_ui_loading(self: &mut Self, s: &str) -> Result<(), AppError> {
         let mut buf: String<U64> = String::new();
@@ -114,25 +187,30 @@ impl Display {
     }
 
     #[inline]
-    fn render_info_screen(self: &mut Self, info: &InfoScreen) -> Result<(), AppError> {
+    fn render_info_screen(self: &mut Self, ps: &PS, info: &InfoScreen) -> Result<(), AppError> {
         self.render_ps_channel(
             0,
-            &info.ch1,
+            &ps.ch1,
             info.uich.as_ref().map(|u| &u.ch1),
             &info.vsel,
             info.chsel.is_selected(ChSelected::Ch1),
-            false,
         )?;
 
         self.render_ps_channel(
             74,
-            &info.ch2,
+            &ps.ch2,
             info.uich.as_ref().map(|u| &u.ch2),
             &info.vsel,
             info.chsel.is_selected(ChSelected::Ch2),
-            true,
         )?;
 
+        if ps.datalog.running {
+            self.render_small_text("LOG", 56, 1)?;
+            if ps.datalog.failing {
+                self.render_small_text("SD?", 56, 8)?; // rows are kept until it's back
+            }
+        }
+
         Ok(())
     }
 
@@ -143,7 +221,6 @@ impl Display {
         uich: Option<&UIChannel>,
         vsel: &VarSelected,
         chsel: bool,
-        dash_graph: bool,
     ) -> Result<(), AppError> {
         let mut s: String<U32> = String::new();
         let mut iselstr = "=";
@@ -197,6 +274,11 @@ impl Display {
         )
         .draw(&mut self.device)?;
 
+        // highlight the setpoint that's currently limiting the output
+        let sts = ch.active_status();
+        let (vfg, vbg) = highlight_colors(sts.map_or(false, |st| st.is_cv()));
+        let (ifg, ibg) = highlight_colors(sts.map_or(false, |st| st.is_cc()));
+
         s.clear();
         write!(
             s,
@@ -208,7 +290,7 @@ impl Display {
         egtext!(
             text = &s,
             top_left = Point::new(xoff, 25),
-            style = text_style!(font = Font6x8, text_color = BinaryColor::On,)
+            style = text_style!(font = Font6x8, text_color = vfg, background_color = vbg)
         )
         .draw(&mut self.device)?;
 
@@ -223,31 +305,205 @@ impl Display {
         egtext!(
             text = &s,
             top_left = Point::new(xoff, 33),
-            style = text_style!(font = Font6x8, text_color = BinaryColor::On,)
+            style = text_style!(font = Font6x8, text_color = ifg, background_color = ibg)
         )
         .draw(&mut self.device)?;
 
-        match ch.relative_power_samples_itr() {
+        s.clear();
+        write_status(&mut s, ch)?;
+        let (fg, bg) = highlight_colors(ch.fuse.tripped().is_some());
+        egtext!(
+            text = &s,
+            top_left = Point::new(xoff, 43),
+            style = text_style!(font = Font6x6, text_color = fg, background_color = bg)
+        )
+        .draw(&mut self.device)?;
+
+        match ch.autooff.remaining_ms {
+            Some(ms) => {
+                s.clear();
+                write!(s, "off {}", DurationFmt(ms))?;
+                self.render_small_text(&s, xoff, 50)?;
+            }
             None => (),
-            Some(ps) => {
-                let mut x = 0;
-                for p in ps {
-                    let py = ((p * 22.0) as i32).max(0).min(22);
-                    let c = if dash_graph {
-                        if x % 2 == 0 {
-                            BinaryColor::On
-                        } else {
-                            BinaryColor::Off
-                        }
-                    } else {
-                        BinaryColor::On
-                    };
-                    Pixel(Point::new(x, HEIGHT - py), c).draw(&mut self.device)?;
-                    x += 1;
+        }
+
+        if ch.limits.is_set() {
+            s.clear();
+            write!(s, "<{:.1}V {:.2}A", ch.limits.v_max, ch.limits.i_max)?;
+            self.render_small_text(&s, xoff, 57)?;
+        }
+
+        Ok(())
+    }
+
+    /// Menu on the left, status (date, time, logging) on the right
+    #[inline]
+    fn render_menu_screen(self: &mut Self, ps: &PS, ms: &MenuScreen) -> Result<(), AppError> {
+        self.render_list(ms.selected, MENU_ITEMS.iter().map(|m| m.to_str()))?;
+
+        let mut s: String<U32> = String::new();
+        match ps.wall_time {
+            Some(dt) => {
+                write!(s, "{:04}-{:02}-{:02}", dt.year, dt.month, dt.day)?;
+                self.render_small_text(&s, STATUS_X, 2)?;
+                s.clear();
+                write!(s, "{:02}:{:02}:{:02}", dt.hour, dt.minute, dt.second)?;
+                self.render_small_text(&s, STATUS_X, 9)?;
+            }
+            None => self.render_small_text("no clock", STATUS_X, 2)?,
+        }
+
+        if !ps.card_present {
+            self.render_small_text("no card", STATUS_X, 16)?;
+        } else if ps.datalog.running {
+            s.clear();
+            write!(s, "LOG {}", ps.datalog.rows)?;
+            self.render_small_text(&s, STATUS_X, 16)?;
+        }
+
+        Ok(())
+    }
+
+    fn render_stats_screen(self: &mut Self, ps: &PS) -> Result<(), AppError> {
+        self.render_small_text("min", STATS_COLUMNS[0] + 18, 0)?;
+        self.render_small_text("max", STATS_COLUMNS[1] + 18, 0)?;
+        self.render_small_text("avg", STATS_COLUMNS[2] + 18, 0)?;
+
+        self.render_stats_row("1V", 8, &ps.ch1.stats.vout)?;
+        self.render_stats_row("1A", 15, &ps.ch1.stats.iout)?;
+        self.render_stats_row("1W", 22, &ps.ch1.stats.pout)?;
+
+        self.render_stats_row("2V", 31, &ps.ch2.stats.vout)?;
+        self.render_stats_row("2A", 38, &ps.ch2.stats.iout)?;
+        self.render_stats_row("2W", 45, &ps.ch2.stats.pout)?;
+
+        let mut s: String<U32> = String::new();
+        write!(
+            s,
+            "n {} / {}",
+            ps.ch1.stats.vout.count(),
+            ps.ch2.stats.vout.count()
+        )?;
+        self.render_small_text(&s, 0, HEIGHT - 6)?;
+
+        Ok(())
+    }
+
+    fn render_stats_row(self: &mut Self, label: &str, y: i32, st: &Stats) -> Result<(), AppError> {
+        let mut s: String<U32> = String::new();
+
+        self.render_small_text(label, 0, y)?;
+
+        for (x, v) in STATS_COLUMNS
+            .iter()
+            .zip([st.min(), st.max(), st.mean()].iter())
+        {
+            s.clear();
+            write!(s, "{:6.3}", OptF32Fmt(*v))?;
+            self.render_small_text(&s, *x, y)?;
+        }
+
+        Ok(())
+    }
+
+    fn render_graph_screen(self: &mut Self, ps: &PS, gs: &GraphScreen) -> Result<(), AppError> {
+        let hist = &ps.channel(gs.ch).history;
+        let mut s: String<U32> = String::new();
+
+        write!(
+            s,
+            "{}{} {}",
+            gs.ch.to_str(),
+            gs.quantity.unit(),
+            gs.span.to_str()
+        )?;
+        self.render_small_text(&s, 0, 0)?;
+
+        let age_ms = gs.cursor_age_ms();
+        s.clear();
+        if age_ms < 100_000 {
+            write!(s, "-{:.1}s ", age_ms as f32 / 1000.0)?;
+        } else {
+            write!(s, "-{}s ", age_ms / 1000)?;
+        }
+        write!(
+            s,
+            "{:.3}",
+            OptF32Fmt(hist.sample(gs.span, gs.quantity, gs.cursor))
+        )?;
+        self.render_small_text(&s, WIDTH + 1 - 6 * s.len() as i32, 0)?;
+
+        let range = hist.samples_itr(gs.span, gs.quantity).flatten().fold(
+            None,
+            |acc: Option<(f32, f32)>, v| {
+                Some(acc.map_or((v, v), |(lo, hi)| (lo.min(v), hi.max(v))))
+            },
+        );
+
+        let (lo, hi) = match range {
+            None => {
+                return self.render_small_text("<< No data >>", GRAPH_X, HEIGHT / 2);
+            }
+            Some((lo, hi)) => {
+                // autoscale, leave a small margin
+                let mid = (lo + hi) / 2.0;
+                let half = (hi - lo).max(GRAPH_MIN_RANGE) * 0.55;
+                (mid - half, mid + half)
+            }
+        };
+
+        s.clear();
+        write!(s, "{}", AxisFmt(hi))?;
+        self.render_small_text(&s, 0, GRAPH_TOP)?;
+        s.clear();
+        write!(s, "{}", AxisFmt(lo))?;
+        self.render_small_text(&s, 0, HEIGHT - 5)?;
+
+        Line::new(
+            Point::new(GRAPH_X - 2, GRAPH_TOP),
+            Point::new(GRAPH_X - 2, HEIGHT),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+        .draw(&mut self.device)?;
+
+        let scale_y = |v: f32| HEIGHT - ((v - lo) / (hi - lo) * (HEIGHT - GRAPH_TOP) as f32) as i32;
+
+        let mut prev: Option<Point> = None;
+        for (x, v) in (GRAPH_X..).zip(hist.samples_itr(gs.span, gs.quantity)) {
+            match v {
+                None => prev = None,
+                Some(v) => {
+                    let p = Point::new(x, scale_y(v));
+                    match prev {
+                        None => Pixel(p, BinaryColor::On).draw(&mut self.device)?,
+                        Some(pp) => Line::new(pp, p)
+                            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                            .draw(&mut self.device)?,
+                    }
+                    prev = Some(p);
                 }
             }
         }
 
+        // dashed cursor line
+        let cx = GRAPH_X + gs.cursor as i32;
+        for y in (GRAPH_TOP..=HEIGHT).step_by(2) {
+            Pixel(Point::new(cx, y), BinaryColor::On).draw(&mut self.device)?;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn render_small_text(self: &mut Self, s: &str, x: i32, y: i32) -> Result<(), AppError> {
+        egtext!(
+            text = s,
+            top_left = Point::new(x, y),
+            style = text_style!(font = Font6x6, text_color = BinaryColor::On,)
+        )
+        .draw(&mut self.device)?;
+
         Ok(())
     }
 
@@ -261,45 +517,115 @@ impl Display {
             )
             .draw(&mut self.device)?;
         } else {
-            let page_num = pfs.selected / FILES_PER_SCREEN;
-            let begin = page_num * FILES_PER_SCREEN;
-            let end = (begin + FILES_PER_SCREEN).min(pfs.fnames.len());
-            self.render_project_files_page(
-                pfs.selected % FILES_PER_SCREEN,
-                &pfs.fnames[begin..end],
+            self.render_list(pfs.selected, (0..pfs.fnames.len()).map(|i| pfs.label(i)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Name and size at the top, date (or a limits warning), header (or first) lines,
+    /// run/cancel at the bottom
+    fn render_file_preview(self: &mut Self, fp: &FilePreview) -> Result<(), AppError> {
+        let mut s: String<U32> = String::new();
+
+        egtext!(
+            text = split_path(&fp.path).1,
+            top_left = Point::new(0, 0),
+            style = text_style!(
+                font = Font6x8,
+                text_color = BinaryColor::Off,
+                background_color = BinaryColor::On
+            )
+        )
+        .draw(&mut self.device)?;
+
+        write!(s, "{}B", fp.info.size)?;
+        self.render_small_text(&s, WIDTH + 1 - 6 * s.len() as i32, 1)?;
+
+        if fp.limits_ok {
+            s.clear();
+            write!(
+                s,
+                "{}-{:02}-{:02} {:02}:{:02}",
+                fp.info.year, fp.info.month, fp.info.day, fp.info.hour, fp.info.minute
             )?;
+            self.render_small_text(&s, 0, 10)?;
+        } else {
+            egtext!(
+                text = "LIMITS DIFFER",
+                top_left = Point::new(0, 9),
+                style = text_style!(
+                    font = Font6x8,
+                    text_color = BinaryColor::Off,
+                    background_color = BinaryColor::On
+                )
+            )
+            .draw(&mut self.device)?;
+        }
+
+        let mut voffset = 19;
+        for line in fp.lines.iter() {
+            self.render_small_text(line, 0, voffset)?;
+            voffset += 7;
+        }
+
+        for (x, label, run) in [(20, "Cancel", false), (80, "Run", true)].iter() {
+            if fp.run == *run {
+                egtext!(
+                    text = label,
+                    top_left = Point::new(*x, HEIGHT - 8),
+                    style = text_style!(
+                        font = Font6x8,
+                        text_color = BinaryColor::Off,
+                        background_color = BinaryColor::On
+                    )
+                )
+                .draw(&mut self.device)?;
+            } else {
+                egtext!(
+                    text = label,
+                    top_left = Point::new(*x, HEIGHT - 8),
+                    style = text_style!(font = Font6x8, text_color = BinaryColor::On)
+                )
+                .draw(&mut self.device)?;
+            }
         }
 
         Ok(())
     }
 
+    /// Page of a list with a cursor next to the selected item
     #[inline]
-    fn render_project_files_page(
-        self: &mut Self,
-        selected: usize,
-        fnames: &[String<U32>],
-    ) -> Result<(), AppError> {
+    fn render_list<'s, I>(self: &mut Self, selected: usize, items: I) -> Result<(), AppError>
+    where
+        I: Iterator<Item = &'s str>,
+    {
+        let page_num = selected / LINES_PER_SCREEN;
+        let begin = page_num * LINES_PER_SCREEN;
+        self.render_list_page(
+            selected % LINES_PER_SCREEN,
+            items.skip(begin).take(LINES_PER_SCREEN),
+        )
+    }
+
+    #[inline]
+    fn render_list_page<'s, I>(self: &mut Self, selected: usize, items: I) -> Result<(), AppError>
+    where
+        I: Iterator<Item = &'s str>,
+    {
         let mut voffset = 2;
         let mut idx = 0;
 
-        let p1 = Point::new(0, 0);
-        let p2 = Point::new(3, 3);
-        let p3 = Point::new(0, 6);
-        let cursor = Triangle::from_points([p1, p2, p3]);
-
-        for fname in fnames {
+        for item in items {
             egtext!(
-                text = fname.as_str(),
+                text = item,
                 top_left = Point::new(9, voffset),
                 style = text_style!(font = Font6x6, text_color = BinaryColor::On,)
             )
             .draw(&mut self.device)?;
 
             if idx == selected {
-                cursor
-                    .translate(Point::new(3, voffset))
-                    .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
-                    .draw(&mut self.device)?;
+                self.render_list_cursor(voffset)?;
             }
 
             idx += 1;
@@ -311,6 +637,589 @@ impl Display {
         Ok(())
     }
 
+    #[inline]
+    fn render_list_cursor(self: &mut Self, voffset: i32) -> Result<(), AppError> {
+        let p1 = Point::new(0, 0);
+        let p2 = Point::new(3, 3);
+        let p3 = Point::new(0, 6);
+
+        Triangle::from_points([p1, p2, p3])
+            .translate(Point::new(3, voffset))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(&mut self.device)?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn render_settings_screen(
+        self: &mut Self,
+        ps: &PS,
+        ss: &SettingsScreen,
+    ) -> Result<(), AppError> {
+        self.render_settings_list(ps, ss, &SETTINGS, 2, LINES_PER_SCREEN)
+    }
+
+    /// Generator output and effective update interval at the top, settings below
+    fn render_waveform_screen(
+        self: &mut Self,
+        ps: &PS,
+        ws: &WaveformScreen,
+    ) -> Result<(), AppError> {
+        let wf = &ps.waveform;
+        let mut s: String<U32> = String::new();
+
+        write!(
+            s,
+            "{}{} {:.3}",
+            wf.ch.to_str(),
+            match wf.target {
+                VarSelected::V => "V",
+                VarSelected::I => "A",
+            },
+            OptF32Fmt(ws.gen.value)
+        )?;
+        self.render_small_text(&s, 0, 0)?;
+
+        s.clear();
+        write!(s, "{}ms", ws.gen.update_ms)?;
+        self.render_small_text(&s, WIDTH + 1 - 6 * s.len() as i32, 0)?;
+
+        self.render_settings_list(ps, &ws.list, &WAVE_SETTINGS, 9, LINES_PER_SCREEN - 1)
+    }
+
+    /// Profile before the start, progress while charging, summary at the end
+    fn render_charger_screen(self: &mut Self, ps: &PS) -> Result<(), AppError> {
+        let c = match &ps.charger {
+            Some(c) => c,
+            None => return self.render_small_text("No charge profile", 0, 0),
+        };
+
+        let mut s: String<U32> = String::new();
+        let p = &c.profile;
+
+        match c.phase {
+            ChargePhase::Ready => write!(s, "CHARGE READY")?,
+            ChargePhase::CC => write!(s, "CHARGE CC")?,
+            ChargePhase::CV => write!(s, "CHARGE CV")?,
+            ChargePhase::Done(e) => write!(s, "CHARGE {}", e.to_str())?,
+        }
+
+        egtext!(
+            text = &s,
+            top_left = Point::new(0, 0),
+            style = text_style!(
+                font = Font6x8,
+                text_color = BinaryColor::Off,
+                background_color = BinaryColor::On
+            )
+        )
+        .draw(&mut self.device)?;
+
+        s.clear();
+        write!(
+            s,
+            "{} {}x{} {:.2}V",
+            c.ch.to_str(),
+            p.chemistry.to_str(),
+            p.cells,
+            p.v_target()
+        )?;
+        self.render_small_text(&s, 0, 11)?;
+
+        s.clear();
+        write!(s, "{:.2}A term {:.2}A", p.i_charge, p.i_term)?;
+        self.render_small_text(&s, 0, 18)?;
+
+        s.clear();
+        write!(s, "{}", DurationFmt(c.elapsed_ms))?;
+        if p.max_ah > 0.0 {
+            write!(s, " max {:.2}Ah", p.max_ah)?;
+        }
+        self.render_small_text(&s, 0, 27)?;
+
+        let psch = ps.channel(c.ch);
+        s.clear();
+        write!(
+            s,
+            "{:6.3}V {:6.3}A",
+            OptF32Fmt(c.final_v.or(psch.vout)),
+            OptF32Fmt(psch.iout.filter(|_| c.phase.is_charging()))
+        )?;
+        self.render_small_text(&s, 0, 36)?;
+
+        s.clear();
+        write!(s, "{:.3}Ah {:.2}Wh", c.ah, c.wh)?;
+        self.render_small_text(&s, 0, 43)?;
+
+        let hint = match c.phase {
+            ChargePhase::Ready => "press: start",
+            ChargePhase::CC | ChargePhase::CV => "press 1s: stop",
+            ChargePhase::Done(_) => "press: clear",
+        };
+        self.render_small_text(hint, 0, HEIGHT - 6)?;
+
+        Ok(())
+    }
+
+    /// Progress and saved file at the top, settings or the I-V plot below
+    fn render_sweep_screen(self: &mut Self, ps: &PS, ss: &SweepScreen) -> Result<(), AppError> {
+        let run = &ss.run;
+        let mut s: String<U32> = String::new();
+
+        write!(
+            s,
+            "IV {} {}/{}",
+            match run.state {
+                SweepState::Idle => "idle",
+                SweepState::Running => "run",
+                SweepState::Done => "done",
+            },
+            run.points.len(),
+            match run.state {
+                SweepState::Idle => ps.sweep.points,
+                _ => run.active.points,
+            }
+        )?;
+        self.render_small_text(&s, 0, 0)?;
+
+        match &run.saved {
+            Some(fname) => self.render_small_text(fname, WIDTH + 1 - 6 * fname.len() as i32, 0)?,
+            None => (),
+        }
+
+        if !ss.show_plot {
+            return self.render_settings_list(
+                ps,
+                &ss.list,
+                &SWEEP_SETTINGS,
+                9,
+                LINES_PER_SCREEN - 1,
+            );
+        }
+
+        // autoscale both axes, I over V
+        let range = |f: fn(&SweepPoint) -> f32| {
+            run.points
+                .iter()
+                .map(f)
+                .fold(None, |acc: Option<(f32, f32)>, v| {
+                    Some(acc.map_or((v, v), |(lo, hi)| (lo.min(v), hi.max(v))))
+                })
+        };
+        let ((vlo, vhi), (ilo, ihi)) = match range(|p| p.vout).zip(range(|p| p.iout)) {
+            None => return self.render_small_text("<< No data >>", GRAPH_X, HEIGHT / 2),
+            Some(((vlo, vhi), (ilo, ihi))) => (
+                (vlo, vlo + (vhi - vlo).max(GRAPH_MIN_RANGE)),
+                (ilo, ilo + (ihi - ilo).max(GRAPH_MIN_RANGE)),
+            ),
+        };
+
+        s.clear();
+        write!(s, "{}", AxisFmt(ihi))?;
+        self.render_small_text(&s, 0, GRAPH_TOP)?;
+        s.clear();
+        write!(s, "{}", AxisFmt(ilo))?;
+        self.render_small_text(&s, 0, HEIGHT - 5)?;
+
+        // V span in the bottom right corner, rising I-V curves leave it empty
+        s.clear();
+        write!(s, "{:.2}-{:.2}V", vlo, vhi)?;
+        self.render_small_text(&s, WIDTH + 1 - 6 * s.len() as i32, HEIGHT - 5)?;
+
+        Line::new(
+            Point::new(GRAPH_X - 2, GRAPH_TOP),
+            Point::new(GRAPH_X - 2, HEIGHT),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+        .draw(&mut self.device)?;
+
+        let scale = |p: &SweepPoint| {
+            Point::new(
+                GRAPH_X + ((p.vout - vlo) / (vhi - vlo) * (WIDTH - GRAPH_X) as f32) as i32,
+                HEIGHT - ((p.iout - ilo) / (ihi - ilo) * (HEIGHT - GRAPH_TOP) as f32) as i32,
+            )
+        };
+
+        let mut prev: Option<Point> = None;
+        for p in run.points.iter().map(scale) {
+            match prev {
+                None => Pixel(p, BinaryColor::On).draw(&mut self.device)?,
+                Some(pp) => Line::new(pp, p)
+                    .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                    .draw(&mut self.device)?,
+            }
+            prev = Some(p);
+        }
+
+        Ok(())
+    }
+
+    /// Target list, then raw/corrected readings and the reference value, then the result
+    fn render_cal_screen(self: &mut Self, ps: &PS, cs: &CalScreen) -> Result<(), AppError> {
+        let (ch, target) = cs.target();
+        let psch = ps.channel(ch);
+        let cal = psch.cal.get(target);
+        let unit = match target {
+            VarSelected::V => "V",
+            VarSelected::I => "A",
+        };
+        let mut s: String<U32> = String::new();
+
+        match cs.step {
+            CalStep::Select => write!(s, "CALIBRATE")?,
+            CalStep::Point(n) => write!(s, "CAL CH{}{} point {}/2", ch.to_str(), unit, n + 1)?,
+            CalStep::Done => write!(s, "CAL CH{}{} done", ch.to_str(), unit)?,
+        }
+        egtext!(
+            text = &s,
+            top_left = Point::new(0, 0),
+            style = text_style!(
+                font = Font6x8,
+                text_color = BinaryColor::Off,
+                background_color = BinaryColor::On
+            )
+        )
+        .draw(&mut self.device)?;
+
+        match cs.step {
+            CalStep::Select => {
+                let mut voffset = 11;
+                for (idx, (c, t)) in CAL_TARGETS.iter().enumerate() {
+                    let cl = ps.channel(*c).cal.get(*t);
+                    s.clear();
+                    write!(
+                        s,
+                        "{}{} {:.5} {:+.4}",
+                        c.to_str(),
+                        target_str(*t),
+                        cl.gain,
+                        cl.offset
+                    )?;
+                    self.render_small_text(&s, 9, voffset)?;
+                    if idx == cs.selected {
+                        self.render_list_cursor(voffset)?;
+                    }
+                    voffset += 7;
+                }
+                self.render_small_text("press:start 1s:reset", 0, HEIGHT - 6)?;
+            }
+            CalStep::Point(_) => {
+                let v = cs.reading(psch);
+                s.clear();
+                write!(s, "raw {:8.4}{}", OptF32Fmt(v.map(|v| cal.raw(v))), unit)?;
+                self.render_small_text(&s, 0, 11)?;
+
+                s.clear();
+                write!(s, "cal {:8.4}{}", OptF32Fmt(v), unit)?;
+                self.render_small_text(&s, 0, 18)?;
+
+                s.clear();
+                write!(s, "ref {:8.4}{}", cs.ref_val, unit)?;
+                egtext!(
+                    text = &s,
+                    top_left = Point::new(0, 29),
+                    style = text_style!(
+                        font = Font6x8,
+                        text_color = BinaryColor::Off,
+                        background_color = BinaryColor::On
+                    )
+                )
+                .draw(&mut self.device)?;
+
+                self.render_small_text("turn: meter reading", 0, HEIGHT - 13)?;
+                self.render_small_text("press: take point", 0, HEIGHT - 6)?;
+            }
+            CalStep::Done => {
+                match cs.result {
+                    Some(c) => {
+                        s.clear();
+                        write!(s, "gain   {:.6}", c.gain)?;
+                        self.render_small_text(&s, 0, 11)?;
+                        s.clear();
+                        write!(s, "offset {:+.5}{}", c.offset, unit)?;
+                        self.render_small_text(&s, 0, 18)?;
+                        s.clear();
+                        if cs.saved {
+                            write!(s, "saved to {}", CAL_FILE)?;
+                        } else {
+                            write!(s, "not saved")?;
+                        }
+                        self.render_small_text(&s, 0, 29)?;
+                    }
+                    None => self.render_small_text("points too far off", 0, 11)?,
+                }
+                self.render_small_text("press: back", 0, HEIGHT - 6)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Setpoints being saved, then the file they went to
+    fn render_save_screen(self: &mut Self, ps: &PS, ss: &SaveScreen) -> Result<(), AppError> {
+        egtext!(
+            text = "SNAPSHOT",
+            top_left = Point::new(0, 0),
+            style = text_style!(
+                font = Font6x8,
+                text_color = BinaryColor::Off,
+                background_color = BinaryColor::On
+            )
+        )
+        .draw(&mut self.device)?;
+
+        let mut s: String<U32> = String::new();
+        let mut voffset = 11;
+        for ch in [Channel::Ch1, Channel::Ch2].iter() {
+            let psch = ps.channel(*ch);
+            s.clear();
+            write!(
+                s,
+                "{} {:.3}V {:.3}A {}",
+                ch.to_str(),
+                OptF32Fmt(psch.vset),
+                OptF32Fmt(psch.iset),
+                match psch.out {
+                    Some(true) => "on",
+                    Some(false) => "off",
+                    None => "",
+                }
+            )?;
+            self.render_small_text(&s, 0, voffset)?;
+            s.clear();
+            write!(s, "  OV {:.3}V", OptF32Fmt(psch.ovset))?;
+            self.render_small_text(&s, 0, voffset + 7)?;
+            voffset += 16;
+        }
+
+        s.clear();
+        match (&ss.saved, ss.save_pending) {
+            (Some(fname), _) => write!(s, "saved as {}", fname)?,
+            (None, true) => write!(s, "saving...")?,
+            (None, false) => write!(s, "not saved")?,
+        }
+        self.render_small_text(&s, 0, voffset + 2)?;
+
+        self.render_small_text("press: save again", 0, HEIGHT - 6)?;
+
+        Ok(())
+    }
+
+    /// Saved setpoints and screen, cancel/restore at the bottom
+    fn render_restore_screen(self: &mut Self, rs: &RestoreScreen) -> Result<(), AppError> {
+        egtext!(
+            text = "RESTORE SESSION",
+            top_left = Point::new(0, 0),
+            style = text_style!(
+                font = Font6x8,
+                text_color = BinaryColor::Off,
+                background_color = BinaryColor::On
+            )
+        )
+        .draw(&mut self.device)?;
+
+        let mut s: String<U32> = String::new();
+        let mut voffset = 11;
+        for (i, ch) in [Channel::Ch1, Channel::Ch2].iter().enumerate() {
+            s.clear();
+            write!(
+                s,
+                "{} {:.3}V {:.3}A",
+                ch.to_str(),
+                rs.session.vset[i],
+                rs.session.iset[i]
+            )?;
+            self.render_small_text(&s, 0, voffset)?;
+            voffset += 8;
+        }
+
+        s.clear();
+        write!(
+            s,
+            "{}, outputs off",
+            rs.session.screen.map_or("Info", |item| item.to_str())
+        )?;
+        self.render_small_text(&s, 0, voffset + 2)?;
+
+        for (x, label, restore) in [(20, "Cancel", false), (74, "Restore", true)].iter() {
+            if rs.restore == *restore {
+                egtext!(
+                    text = label,
+                    top_left = Point::new(*x, HEIGHT - 8),
+                    style = text_style!(
+                        font = Font6x8,
+                        text_color = BinaryColor::Off,
+                        background_color = BinaryColor::On
+                    )
+                )
+                .draw(&mut self.device)?;
+            } else {
+                egtext!(
+                    text = label,
+                    top_left = Point::new(*x, HEIGHT - 8),
+                    style = text_style!(font = Font6x8, text_color = BinaryColor::On)
+                )
+                .draw(&mut self.device)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Label on the left, value on the right (highlighted while editing)
+    fn render_settings_list(
+        self: &mut Self,
+        ps: &PS,
+        ss: &SettingsScreen,
+        items: &[Setting],
+        top: i32,
+        lines: usize,
+    ) -> Result<(), AppError> {
+        let begin = (ss.selected / lines) * lines;
+        let mut voffset = top;
+        let mut s: String<U32> = String::new();
+
+        for (idx, st) in items.iter().enumerate().skip(begin).take(lines) {
+            s.clear();
+            st.write_label(&mut s)?;
+            self.render_small_text(&s, 9, voffset)?;
+
+            s.clear();
+            st.write_value(ps, &mut s)?;
+            let (fg, bg) = highlight_colors(ss.editing && idx == ss.selected);
+            egtext!(
+                text = &s,
+                top_left = Point::new(WIDTH + 1 - 6 * s.len() as i32, voffset),
+                style = text_style!(font = Font6x6, text_color = fg, background_color = bg)
+            )
+            .draw(&mut self.device)?;
+
+            if idx == ss.selected {
+                self.render_list_cursor(voffset)?;
+            }
+
+            voffset += 7;
+        }
+
+        Ok(())
+    }
+
+    /// Step, time left in it and cycle at the top, channel readings below
+    fn render_sequencer_screen(self: &mut Self, ps: &PS, sq: &Sequencer) -> Result<(), AppError> {
+        let mut s: String<U32> = String::new();
+
+        let state = match sq.state {
+            RunState::Running => "RUN",
+            RunState::Paused => "PAUSE",
+            RunState::Done => "DONE",
+        };
+        write!(s, "SEQ {}", state)?;
+
+        egtext!(
+            text = &s,
+            top_left = Point::new(0, 0),
+            style = text_style!(
+                font = Font6x8,
+                text_color = BinaryColor::Off,
+                background_color = BinaryColor::On
+            )
+        )
+        .draw(&mut self.device)?;
+
+        s.clear();
+        if sq.cycles() == 0 {
+            write!(s, "cycle {}", sq.cycle())?;
+        } else {
+            write!(s, "cycle {}/{}", sq.cycle(), sq.cycles())?;
+        }
+        self.render_small_text(&s, WIDTH + 1 - 6 * s.len() as i32, 1)?;
+
+        s.clear();
+        write!(s, "step {}/{}", sq.step(), sq.num_steps())?;
+        self.render_small_text(&s, 0, 12)?;
+
+        s.clear();
+        write!(s, "{:.1}s", sq.remaining_ms() as f32 / 1000.0)?;
+        self.render_small_text(&s, WIDTH + 1 - 6 * s.len() as i32, 12)?;
+
+        for (y, ch) in [(25, Channel::Ch1), (35, Channel::Ch2)].iter() {
+            let psch = ps.channel(*ch);
+            s.clear();
+            write!(
+                s,
+                "{} {:6.3}V {:6.3}A ",
+                ch.to_str(),
+                OptF32Fmt(psch.vout),
+                OptF32Fmt(psch.iout)
+            )?;
+            write_status(&mut s, psch)?;
+            self.render_small_text(&s, 0, *y)?;
+        }
+
+        self.render_small_text("press: pause, btn: stop", 0, HEIGHT - 6)?;
+
+        Ok(())
+    }
+
+    /// Line being run and what's been printed at the top, variables and channel readings below
+    fn render_script_screen(self: &mut Self, ps: &PS, sr: &ScriptRun) -> Result<(), AppError> {
+        let mut s: String<U32> = String::new();
+
+        let state = match sr.state {
+            RunState::Running => "RUN",
+            RunState::Paused => "PAUSE",
+            RunState::Done => "DONE",
+        };
+        write!(s, "SCRIPT {}", state)?;
+
+        egtext!(
+            text = &s,
+            top_left = Point::new(0, 0),
+            style = text_style!(
+                font = Font6x8,
+                text_color = BinaryColor::Off,
+                background_color = BinaryColor::On
+            )
+        )
+        .draw(&mut self.device)?;
+
+        s.clear();
+        write!(s, "ln {}", sr.line())?;
+        if sr.remaining_ms() > 0 {
+            write!(s, " {:.1}s", sr.remaining_ms() as f32 / 1000.0)?;
+        }
+        self.render_small_text(&s, WIDTH + 1 - 6 * s.len() as i32, 1)?;
+
+        self.render_small_text(&sr.printed, 0, 11)?;
+
+        let mut voffset = 20;
+        for (name, v) in sr.vars().take(3) {
+            s.clear();
+            write!(s, "{}={:.3}", name, v)?;
+            self.render_small_text(&s, 0, voffset)?;
+            voffset += 7;
+        }
+
+        for (y, ch) in [(42, Channel::Ch1), (50, Channel::Ch2)].iter() {
+            let psch = ps.channel(*ch);
+            s.clear();
+            write!(
+                s,
+                "{} {:6.3}V {:6.3}A ",
+                ch.to_str(),
+                OptF32Fmt(psch.vout),
+                OptF32Fmt(psch.iout)
+            )?;
+            write_status(&mut s, psch)?;
+            self.render_small_text(&s, 0, *y)?;
+        }
+
+        self.render_small_text("press: pause, btn: stop", 0, HEIGHT - 6)?;
+
+        Ok(())
+    }
+
     #[inline]
     fn debug_delay(&mut self) -> Result<(), AppError> {
         let mut delay = AsmDelay {};
@@ -342,3 +1251,85 @@ impl core::fmt::Display for OptF32Fmt {
         }
     }
 }
+
+/// (text, background) colors
+#[inline]
+fn highlight_colors(highlight: bool) -> (BinaryColor, BinaryColor) {
+    if highlight {
+        (BinaryColor::Off, BinaryColor::On)
+    } else {
+        (BinaryColor::On, BinaryColor::Off)
+    }
+}
+
+/// Protection faults, then regulation mode (CV/CC) or OFF
+fn write_status<S>(s: &mut String<S>, ch: &PSChannel) -> Result<(), AppError>
+where
+    S: ArrayLength<u8>,
+{
+    // latched until acknowledged, the output is off anyway
+    match ch.fuse.tripped() {
+        Some(FuseTrip::Current(i)) => {
+            write!(s, "FUSE {:.2}A", i)?;
+            return Ok(());
+        }
+        Some(FuseTrip::Power(w)) => {
+            write!(s, "FUSE {:.1}W", w)?;
+            return Ok(());
+        }
+        None => (),
+    }
+
+    match ch.sts {
+        Some(st) => {
+            for (fault, name) in [
+                (st.is_ov(), "OV "),
+                (st.is_oc(), "OC "),
+                (st.is_ot(), "OT "),
+            ]
+            .iter()
+            {
+                if *fault {
+                    s.push_str(name).map_err(|_| AppError::FmtError)?;
+                }
+            }
+        }
+        None => (),
+    }
+
+    match (ch.out, &ch.ramp, ch.active_status()) {
+        (Some(false), _, _) => write!(s, "OFF")?,
+        (_, Some(r), _) => write!(s, "RAMP {:.0}%", r.progress() * 100.0)?,
+        (_, _, Some(st)) if st.is_cc() => write!(s, "CC")?,
+        (_, _, Some(st)) if st.is_cv() => write!(s, "CV")?,
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// h:mm:ss
+struct DurationFmt(u64);
+
+impl core::fmt::Display for DurationFmt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let secs = self.0 / 1000;
+        write!(f, "{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+/// Short (4 char) axis label
+struct AxisFmt(f32);
+
+impl core::fmt::Display for AxisFmt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let v = self.0;
+        if v.abs() >= 100.0 {
+            write!(f, "{:4.0}", v)
+        } else if v.abs() >= 10.0 {
+            write!(f, "{:4.1}", v)
+        } else {
+            write!(f, "{:4.2}", v)
+        }
+    }
+}
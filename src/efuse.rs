@@ -0,0 +1,84 @@
+//! Software electronic fuse.
+//!
+//! The instrument's OCP trips instantly at the current limit,
+//! this one switches the output off when IOUT (or output power) stays
+//! above the trip level for longer than the trip delay.
+//! A trip is latched, the output is kept off until it's acknowledged.
+
+use crate::clock::Millis;
+
+/// Per channel fuse settings
+#[derive(Copy, Clone)]
+pub struct EFuse {
+    pub enabled: bool,
+    pub trip_i: f32,
+    pub trip_w: f32, // 0 is no power trip
+    pub delay_ms: u32,
+}
+
+impl EFuse {
+    pub const fn new() -> Self {
+        EFuse {
+            enabled: false,
+            trip_i: 1.0,
+            trip_w: 0.0,
+            delay_ms: 100,
+        }
+    }
+}
+
+/// What tripped the fuse, with the reading that did it
+#[derive(Copy, Clone)]
+pub enum FuseTrip {
+    Current(f32),
+    Power(f32),
+}
+
+/// Trip detection and the latched trip
+pub struct Fuse {
+    over_since: Option<Millis>,
+    tripped: Option<FuseTrip>,
+}
+
+impl Fuse {
+    pub const fn new() -> Self {
+        Fuse {
+            over_since: None,
+            tripped: None,
+        }
+    }
+
+    #[inline]
+    pub fn tripped(&self) -> Option<FuseTrip> {
+        self.tripped
+    }
+
+    #[inline]
+    pub fn acknowledge(&mut self) {
+        self.tripped = None;
+    }
+
+    /// True when it trips, readings are None while the output is off
+    pub fn check(&mut self, now: Millis, ef: &EFuse, iout: Option<f32>, pout: Option<f32>) -> bool {
+        let over = match (iout, pout) {
+            _ if !ef.enabled || self.tripped.is_some() => None,
+            (Some(i), _) if i > ef.trip_i => Some(FuseTrip::Current(i)),
+            (_, Some(w)) if ef.trip_w > 0.0 && w > ef.trip_w => Some(FuseTrip::Power(w)),
+            _ => None,
+        };
+
+        match over {
+            Some(trip) => {
+                let since = *self.over_since.get_or_insert(now);
+                if now - since >= ef.delay_ms as u64 {
+                    self.over_since = None;
+                    self.tripped = Some(trip);
+                    return true;
+                }
+            }
+            None => self.over_since = None,
+        }
+
+        false
+    }
+}
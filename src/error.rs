@@ -8,11 +8,15 @@ pub enum AppError {
     Duh,
     FmtError,
     SDError,
+    NoCard, // missing or not answering
     UsbSerialError,
     UartSerialError,
     DisplayError(&'static str),
     ParseError,
     ProjectFileError,
+    LimitError,
+    InstrumentError(u8), // ERR? code, see the instrument manual
+    NoResponse,
 }
 
 impl From<Infallible> for AppError {
@@ -0,0 +1,168 @@
+//! Optional metadata header at the top of project files.
+//!
+//! ```text
+//! # title: Li-ion cell discharge
+//! # description: 1A down to 3V on ch 2
+//! # channels: 2
+//! # limit: 2 4.2 1.5
+//! ```
+//!
+//! `key: value` comment lines before the first command or directive,
+//! other comments are left alone. `description` may be repeated,
+//! `limit <ch> <max volts> <max amps>` declares the soft limits the file
+//! was written for, it doesn't run unless they match the current ones.
+
+use core::str::from_utf8;
+
+use heapless::{consts::*, String, Vec};
+use num_traits::float::FloatCore;
+
+use crate::{
+    directive::{next_arg_str, parse_arg},
+    limits::*,
+    prelude::*,
+    protocol::Channel,
+};
+
+/// Declared and current limits closer than this are the same
+const LIMIT_TOLERANCE: f32 = 0.0005;
+
+pub struct FileHeader {
+    pub title: Option<String<U32>>,
+    pub description: Vec<String<U32>, U3>,
+    pub channels: Vec<Channel, U2>,
+    pub limits: [Option<(u16, SoftLimits)>; 2], // with the line they're declared on
+    pub error_line: Option<u16>,                // first header line that doesn't parse
+}
+
+impl FileHeader {
+    pub fn new() -> Self {
+        FileHeader {
+            title: None,
+            description: Vec::new(),
+            channels: Vec::new(),
+            limits: [None, None],
+            error_line: None,
+        }
+    }
+
+    /// Any key at all
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.description.is_empty()
+            && self.channels.is_empty()
+            && self.limits.iter().all(|l| l.is_none())
+            && self.error_line.is_none()
+    }
+
+    /// Parse a line (1 based `line_no`), false once past the header
+    pub fn parse_line(&mut self, line_no: u16, line: &[u8]) -> bool {
+        let line = match from_utf8(line) {
+            Ok(l) => l.trim(),
+            Err(_) => return false,
+        };
+        if line.is_empty() {
+            return true;
+        }
+        if !line.starts_with('#') {
+            return false;
+        }
+
+        let comment = line.trim_start_matches('#').trim();
+        let (key, value) = match comment.find(':') {
+            Some(i) => (comment[..i].trim(), comment[i + 1..].trim()),
+            None => return true,
+        };
+        let res = match key {
+            "title" => {
+                self.title = Some(truncated(value));
+                Ok(())
+            }
+            "description" => {
+                self.description.push(truncated(value)).ok(); // only the first few
+                Ok(())
+            }
+            "channels" => self.parse_channels(value),
+            "limit" => self.parse_limit(line_no, value),
+            _ => Ok(()),
+        };
+        if res.is_err() && self.error_line.is_none() {
+            self.error_line = Some(line_no);
+        }
+
+        true
+    }
+
+    fn parse_channels(&mut self, value: &str) -> Result<(), AppError> {
+        self.channels.clear();
+        for c in value.split_whitespace() {
+            let ch = Channel::parse(c)?;
+            if !self.channels.contains(&ch) {
+                self.channels.push(ch).map_err(|_| AppError::Duh)?;
+            }
+        }
+        if self.channels.is_empty() {
+            Err(AppError::ProjectFileError)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn parse_limit(&mut self, line_no: u16, value: &str) -> Result<(), AppError> {
+        let mut args = value.split_whitespace();
+        let ch = Channel::parse(next_arg_str(&mut args)?)?;
+        let limits = SoftLimits {
+            v_max: parse_arg(next_arg_str(&mut args)?)?,
+            i_max: parse_arg(next_arg_str(&mut args)?)?,
+        };
+        if !limits.is_valid() {
+            return Err(AppError::ProjectFileError);
+        }
+        let i = match ch {
+            Channel::Ch1 => 0,
+            Channel::Ch2 => 1,
+        };
+        self.limits[i] = Some((line_no, limits));
+        Ok(())
+    }
+
+    /// Declared limits are the current ones (channels without one are fine)
+    pub fn limits_match(&self, l1: &SoftLimits, l2: &SoftLimits) -> bool {
+        self.mismatched_limit(l1, l2).is_none()
+    }
+
+    /// Line of the first declared limit that isn't the current one
+    fn mismatched_limit(&self, l1: &SoftLimits, l2: &SoftLimits) -> Option<u16> {
+        self.limits
+            .iter()
+            .zip([l1, l2].iter())
+            .filter_map(|(declared, current)| declared.map(|(line_no, l)| (line_no, l, current)))
+            .find(|(_, l, current)| {
+                (l.v_max - current.v_max).abs() > LIMIT_TOLERANCE
+                    || (l.i_max - current.i_max).abs() > LIMIT_TOLERANCE
+            })
+            .map(|(line_no, _, _)| line_no)
+    }
+
+    /// OK to run with the current limits, the error comes with the header line
+    pub fn check(&self, l1: &SoftLimits, l2: &SoftLimits) -> Result<(), (AppError, u16)> {
+        if let Some(line_no) = self.error_line {
+            return Err((AppError::ProjectFileError, line_no));
+        }
+        match self.mismatched_limit(l1, l2) {
+            Some(line_no) => Err((AppError::LimitError, line_no)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// As much of a string as fits
+pub fn truncated(s: &str) -> String<U32> {
+    let mut t = String::new();
+    for c in s.chars() {
+        if t.push(c).is_err() {
+            break;
+        }
+    }
+    t
+}
@@ -0,0 +1,236 @@
+//! Channel readings history on a fixed time base
+
+use crate::clock::Millis;
+
+/// Points per history level (one per graph column)
+pub const HISTORY_LEN: usize = 100;
+
+/// Base sample period, all levels are decimated from it
+pub const SAMPLE_PERIOD_MS: u32 = 100;
+
+// Don't spend too long catching up after a stall, a full top level is enough
+const MAX_CATCH_UP_SAMPLES: u64 = HISTORY_LEN as u64 * 360;
+
+/// Time span of a history level
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Span {
+    S10,
+    M1,
+    M10,
+    H1,
+}
+
+const SPANS: [Span; 4] = [Span::S10, Span::M1, Span::M10, Span::H1];
+
+impl Span {
+    pub fn next(&self) -> Self {
+        match self {
+            Span::S10 => Span::M1,
+            Span::M1 => Span::M10,
+            Span::M10 => Span::H1,
+            Span::H1 => Span::S10,
+        }
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Span::S10 => "10s",
+            Span::M1 => "1m",
+            Span::M10 => "10m",
+            Span::H1 => "1h",
+        }
+    }
+
+    /// Number of base samples averaged into one point
+    pub fn decimation(&self) -> u32 {
+        match self {
+            Span::S10 => 1,
+            Span::M1 => 6,
+            Span::M10 => 60,
+            Span::H1 => 360,
+        }
+    }
+
+    #[inline]
+    pub fn sample_period_ms(&self) -> u32 {
+        SAMPLE_PERIOD_MS * self.decimation()
+    }
+
+    #[inline]
+    fn idx(&self) -> usize {
+        match self {
+            Span::S10 => 0,
+            Span::M1 => 1,
+            Span::M10 => 2,
+            Span::H1 => 3,
+        }
+    }
+}
+
+/// What's recorded
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Quantity {
+    V,
+    I,
+    P,
+}
+
+impl Quantity {
+    pub fn next(&self) -> Self {
+        match self {
+            Quantity::V => Quantity::I,
+            Quantity::I => Quantity::P,
+            Quantity::P => Quantity::V,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Quantity::V => "V",
+            Quantity::I => "A",
+            Quantity::P => "W",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Sample {
+    v: f32,
+    i: f32,
+    p: f32,
+    valid: bool, // no readings yet
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        v: 0.0,
+        i: 0.0,
+        p: 0.0,
+        valid: false,
+    };
+
+    fn new(vout: Option<f32>, iout: Option<f32>) -> Self {
+        match vout.zip(iout) {
+            Some((v, i)) => Sample {
+                v,
+                i,
+                p: v.max(0.0) * i.max(0.0),
+                valid: true,
+            },
+            None => Sample::EMPTY,
+        }
+    }
+
+    #[inline]
+    fn get(&self, q: Quantity) -> Option<f32> {
+        if self.valid {
+            Some(match q {
+                Quantity::V => self.v,
+                Quantity::I => self.i,
+                Quantity::P => self.p,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Circular buffer of averaged samples
+struct Level {
+    samples: [Sample; HISTORY_LEN],
+    idx: usize,
+    acc: Sample,
+    acc_valid: u32,
+    acc_total: u32,
+}
+
+impl Level {
+    const fn new() -> Self {
+        Level {
+            samples: [Sample::EMPTY; HISTORY_LEN],
+            idx: 0,
+            acc: Sample::EMPTY,
+            acc_valid: 0,
+            acc_total: 0,
+        }
+    }
+
+    fn push(&mut self, s: &Sample, decimation: u32) {
+        if s.valid {
+            self.acc.v += s.v;
+            self.acc.i += s.i;
+            self.acc.p += s.p;
+            self.acc_valid += 1;
+        }
+        self.acc_total += 1;
+
+        if self.acc_total >= decimation {
+            let n = self.acc_valid.max(1) as f32;
+            self.samples[self.idx] = Sample {
+                v: self.acc.v / n,
+                i: self.acc.i / n,
+                p: self.acc.p / n,
+                valid: self.acc_valid > 0,
+            };
+
+            self.idx += 1;
+            if self.idx >= self.samples.len() {
+                self.idx = 0;
+            }
+
+            self.acc = Sample::EMPTY;
+            self.acc_valid = 0;
+            self.acc_total = 0;
+        }
+    }
+
+    /// Oldest first
+    fn samples_itr(&self) -> impl Iterator<Item = &Sample> {
+        self.samples[self.idx..]
+            .iter()
+            .chain(self.samples[0..self.idx].iter())
+    }
+}
+
+/// Latest readings sampled every SAMPLE_PERIOD_MS, decimated into longer spans
+pub struct History {
+    levels: [Level; 4],
+    last_sample: Option<Millis>,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        History {
+            levels: [Level::new(), Level::new(), Level::new(), Level::new()],
+            last_sample: None,
+        }
+    }
+
+    /// Record current readings once for every sample period elapsed since last update
+    pub fn update(&mut self, now: Millis, vout: Option<f32>, iout: Option<f32>) {
+        let last = *self.last_sample.get_or_insert(now);
+        let n = now.saturating_sub(last) / SAMPLE_PERIOD_MS as u64;
+        if n == 0 {
+            return;
+        }
+
+        self.last_sample = Some(last + n * SAMPLE_PERIOD_MS as u64);
+
+        let s = Sample::new(vout, iout);
+        for _ in 0..n.min(MAX_CATCH_UP_SAMPLES) {
+            for span in SPANS.iter() {
+                self.levels[span.idx()].push(&s, span.decimation());
+            }
+        }
+    }
+
+    /// HISTORY_LEN points, oldest first, None where there were no readings
+    pub fn samples_itr(&self, span: Span, q: Quantity) -> impl Iterator<Item = Option<f32>> + '_ {
+        self.levels[span.idx()].samples_itr().map(move |s| s.get(q))
+    }
+
+    /// Single point, 0 is the oldest
+    pub fn sample(&self, span: Span, q: Quantity, idx: usize) -> Option<f32> {
+        self.samples_itr(span, q).nth(idx).flatten()
+    }
+}
@@ -4,16 +4,36 @@
 #[macro_use]
 pub mod macros;
 
+pub mod autooff;
 pub mod button;
+pub mod calibration;
+pub mod charger;
+pub mod clock;
+pub mod config;
 pub mod consts;
+pub mod datalog;
 pub mod delay;
+pub mod directive;
 pub mod display;
+pub mod efuse;
 pub mod error;
+pub mod header;
+pub mod history;
+pub mod limits;
 pub mod line;
+pub mod link;
 pub mod model;
 pub mod protocol;
 pub mod rotary_encoder;
+pub mod script;
 pub mod sdcard;
+pub mod sequencer;
+pub mod session;
+pub mod settings;
+pub mod snapshot;
+pub mod softstart;
+pub mod stats;
+pub mod sweep;
 pub mod time;
 pub mod types;
 pub mod uart_serial;
@@ -0,0 +1,93 @@
+//! User soft limits, keep setpoints under what the load can take
+//! (on top of the instrument's own maxima)
+
+use core::str::from_utf8;
+
+use crate::{prelude::*, protocol::*};
+
+/// Instrument maxima (6621A, 7V/10A and 20V/4A ranges)
+pub const V_MAX: f32 = 20.0;
+pub const I_MAX: f32 = 10.0;
+
+/// Per channel limits, at the instrument maxima when unset
+#[derive(Copy, Clone)]
+pub struct SoftLimits {
+    pub v_max: f32,
+    pub i_max: f32,
+}
+
+impl SoftLimits {
+    pub const fn new() -> Self {
+        SoftLimits {
+            v_max: V_MAX,
+            i_max: I_MAX,
+        }
+    }
+
+    /// Tighter than the instrument maxima
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.v_max < V_MAX || self.i_max < I_MAX
+    }
+
+    /// Finite and no higher than the instrument maxima (a NaN limit would reject everything)
+    pub fn is_valid(&self) -> bool {
+        self.v_max.is_finite()
+            && self.i_max.is_finite()
+            && self.v_max >= 0.0
+            && self.v_max <= V_MAX
+            && self.i_max >= 0.0
+            && self.i_max <= I_MAX
+    }
+
+    #[inline]
+    pub fn within(&self, cmd: &Command) -> bool {
+        match cmd {
+            Command::Vset { val, .. } => *val <= self.v_max,
+            Command::Iset { val, .. } => *val <= self.i_max,
+            Command::Out { .. } => true,
+        }
+    }
+}
+
+/// Setpoint commands, a line with one of these has to parse to get through
+const SETPOINTS: [&str; 3] = ["VSET", "ISET", "OVSET"];
+
+/// Check VSET/ISET in a line of raw instrument commands ("VSET 1,5;ISET 1 0.1"),
+/// a setpoint without a valid channel and value is rejected too, other commands are
+/// left to the instrument
+pub fn check_line(line: &[u8], l1: &SoftLimits, l2: &SoftLimits) -> Result<(), AppError> {
+    let line = from_utf8(line).map_err(|_| AppError::LimitError)?;
+
+    for stmt in line.split(';') {
+        let mut args = stmt
+            .split(|c: char| c.is_ascii_whitespace() || c == ',')
+            .filter(|a| !a.is_empty());
+
+        let hdr = match args.next() {
+            Some(h) => h,
+            None => continue,
+        };
+        if !SETPOINTS.iter().any(|s| hdr.eq_ignore_ascii_case(s)) {
+            continue;
+        }
+        let lim = match args.next() {
+            Some("1") => l1,
+            Some("2") => l2,
+            _ => return Err(AppError::LimitError),
+        };
+        let val: f32 = args
+            .next()
+            .and_then(|v| v.parse().ok())
+            .filter(|v: &f32| v.is_finite())
+            .ok_or(AppError::LimitError)?;
+
+        if (hdr.eq_ignore_ascii_case("VSET") && val > lim.v_max)
+            || (hdr.eq_ignore_ascii_case("ISET") && val > lim.i_max)
+        {
+            return Err(AppError::LimitError);
+        }
+    }
+
+    Ok(())
+}
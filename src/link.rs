@@ -0,0 +1,114 @@
+//! GPIB link timing, how fast the instrument can be talked to,
+//! and a watchdog noticing when it stops answering
+
+use crate::{clock::Millis, consts::*};
+
+/// Watchdog state change
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum LinkEvent {
+    Lost,
+    Restored,
+}
+
+/// Query round trip time, observed over the serial / GPIB link
+pub struct Link {
+    query_sent: Option<Millis>,
+    rtt_ms: Option<f32>, // moving average
+    pub timeout_ms: u32,
+    pub ping_ms: u32,              // time between channel reading queries
+    pub safe_off: bool,            // switch outputs off once it's back
+    waiting_since: Option<Millis>, // oldest unanswered query
+    lost: bool,
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Link {
+            query_sent: None,
+            rtt_ms: None,
+            timeout_ms: 3000,
+            ping_ms: 1000 / QUERY_PING_HZ,
+            safe_off: true,
+            waiting_since: None,
+            lost: false,
+        }
+    }
+
+    #[inline]
+    pub fn query_sent(&mut self, now: Millis) {
+        self.query_sent = Some(now);
+        self.waiting_since.get_or_insert(now);
+    }
+
+    pub fn query_done(&mut self, now: Millis) {
+        match self.query_sent.take() {
+            Some(t) => {
+                let rtt = now.saturating_sub(t) as f32;
+                self.rtt_ms = Some(self.rtt_ms.map_or(rtt, |avg| avg + (rtt - avg) / 8.0));
+            }
+            None => (),
+        }
+    }
+
+    /// Response parsed fine, the instrument is there
+    #[inline]
+    pub fn response_ok(&mut self) {
+        self.waiting_since = None;
+    }
+
+    /// True (once) when the query in flight should be given up on
+    pub fn query_timed_out(&mut self, now: Millis) -> bool {
+        match self.query_sent {
+            Some(t) if now - t > QUERY_TIMEOUT_MS as u64 => {
+                self.query_sent = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Not polling (loading a file), don't count it against the instrument.
+    /// A lost link stays lost until there's an answer.
+    #[inline]
+    pub fn idle(&mut self) {
+        self.query_sent = None;
+        if !self.lost {
+            self.waiting_since = None;
+        }
+    }
+
+    /// Lost when nothing valid came back for the timeout
+    pub fn check(&mut self, now: Millis) -> Option<LinkEvent> {
+        let lost = self
+            .waiting_since
+            .map_or(false, |t| now - t > self.timeout_ms as u64);
+        if lost == self.lost {
+            return None;
+        }
+
+        self.lost = lost;
+        Some(if lost {
+            LinkEvent::Lost
+        } else {
+            LinkEvent::Restored
+        })
+    }
+
+    #[inline]
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    #[inline]
+    pub fn rtt_ms(&self) -> Option<f32> {
+        self.rtt_ms
+    }
+
+    /// Shortest interval between setpoint updates,
+    /// commands only go out between queries
+    pub fn min_update_ms(&self) -> u32 {
+        self.rtt_ms
+            .map_or(self.ping_ms, |rtt| (rtt as u32).max(self.ping_ms))
+            + COMMAND_DELAY_MS
+    }
+}
@@ -1,13 +1,19 @@
 //! UI model
 
+use core::fmt::Write;
+
 use num_traits::float::FloatCore;
 
 use heapless::{consts::*, ArrayLength, String, Vec};
 
-use rtic::cyccnt::Instant;
 use stm32f4xx_hal::time::MilliSeconds;
 
-use crate::{consts::SYS_FREQ, error::*, line::parse_str, protocol::*, sdcard::*};
+use crate::{
+    autooff::*, calibration::*, charger::*, clock::Millis, config::*, datalog::*, directive::*,
+    efuse::*, error::*, header::*, history::*, limits::*, line::parse_str, link::*, protocol::*,
+    script::*, sdcard::*, sequencer::*, session::*, settings::*, snapshot::*, softstart::*,
+    stats::*, sweep::*, time::DateTime, waveform::*,
+};
 
 // Single channel settings
 pub struct PSChannel {
@@ -15,9 +21,20 @@ pub struct PSChannel {
     pub vout: Option<f32>,
     pub iset: Option<f32>,
     pub iout: Option<f32>,
+    pub ovset: Option<f32>, // overvoltage protection level
     pub out: Option<bool>,
-    power_samples: [f32; 128], // for display only, to draw a simple graph, watts
-    power_sample_idx: usize,   // circular buffer, shift on each screen update
+    pub sts: Option<Status>,
+    pub vout_n: u32, // number of readings so far, tells a fresh reading from a stale one
+    pub iout_n: u32,
+    pub stats: ChannelStats,
+    pub history: History,
+    pub softstart: SoftStart,
+    pub ramp: Option<Ramp>,
+    pub efuse: EFuse,
+    pub fuse: Fuse,
+    pub autooff: AutoOff,
+    pub limits: SoftLimits,
+    pub cal: ChannelCal,
 }
 
 impl PSChannel {
@@ -27,9 +44,20 @@ impl PSChannel {
             vout: None,
             iset: None,
             iout: None,
+            ovset: None,
             out: None,
-            power_samples: [0.0; 128],
-            power_sample_idx: 0,
+            sts: None,
+            vout_n: 0,
+            iout_n: 0,
+            stats: ChannelStats::new(),
+            history: History::new(),
+            softstart: SoftStart::new(),
+            ramp: None,
+            efuse: EFuse::new(),
+            fuse: Fuse::new(),
+            autooff: AutoOff::new(),
+            limits: SoftLimits::new(),
+            cal: ChannelCal::new(),
         }
     }
 
@@ -41,19 +69,45 @@ impl PSChannel {
         match q.header {
             ChannelHeader::Vset => self.vset = Some(parse_str(s)?),
             ChannelHeader::Iset => self.iset = Some(parse_str(s)?),
-            ChannelHeader::Vout => self.vout = Some(parse_str(s)?),
-            ChannelHeader::Iout => self.iout = Some(parse_str(s)?),
+            ChannelHeader::Ovset => self.ovset = Some(parse_str(s)?),
+            ChannelHeader::Vout => {
+                let v = self.cal.vout.apply(parse_str(s)?);
+                self.vout = Some(v);
+                self.vout_n = self.vout_n.wrapping_add(1);
+                self.stats.vout.add(v);
+            }
+            ChannelHeader::Iout => {
+                let i = self.cal.iout.apply(parse_str(s)?);
+                self.iout = Some(i);
+                self.iout_n = self.iout_n.wrapping_add(1);
+                self.stats.iout.add(i);
+                // VOUT and IOUT are polled in turns, sample power with each new current reading
+                self.pout().map(|p| self.stats.pout.add(p));
+            }
             ChannelHeader::Out => {
                 self.out = Some({
                     let i: u32 = parse_str(s)?;
                     i != 0
                 })
             }
+            ChannelHeader::Sts => self.sts = Some(Status(parse_str(s)?)),
         }
 
         Ok(())
     }
 
+    /// Forget setpoints and readings, they're polled again
+    pub fn resync(&mut self) {
+        self.vset = None;
+        self.iset = None;
+        self.vout = None;
+        self.iout = None;
+        self.ovset = None;
+        self.out = None;
+        self.sts = None;
+        self.ramp = None;
+    }
+
     /// Power, watts
     pub fn pout(&self) -> Option<f32> {
         self.vout
@@ -61,34 +115,222 @@ impl PSChannel {
             .map(|(v, i)| v.max(0.0) * i.max(0.0)) // there may be small calibration errs
     }
 
+    /// Status of an enabled output (regulation mode is meaningless when it's off)
+    #[inline]
+    pub fn active_status(&self) -> Option<Status> {
+        self.sts.filter(|_| self.out == Some(true))
+    }
+
     /// Max power with the current config
     pub fn max_pout(&self) -> Option<f32> {
         self.vset.zip(self.iset).map(|(v, i)| v * i)
     }
 
-    /// Saves current power for graph display
-    pub fn sample_current_power(&mut self) {
-        self.pout().map(|p| {
-            self.power_samples[self.power_sample_idx] = p;
-            self.power_sample_idx += 1;
-            if self.power_sample_idx >= self.power_samples.len() {
-                self.power_sample_idx = 0;
+    /// Periodic update
+    #[inline]
+    pub fn tick(
+        &mut self,
+        ch: Channel,
+        now: Millis,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        self.history.update(now, self.vout, self.iout);
+
+        let on = self.out == Some(true);
+        self.fuse.check(
+            now,
+            &self.efuse,
+            self.iout.filter(|_| on),
+            self.pout().filter(|_| on),
+        );
+        // a trip is latched, keep the output off until it's acknowledged
+        if on && self.fuse.tripped().is_some() {
+            cmds.push(Command::Out { ch, on: false })?;
+            self.out = None;
+            self.ramp = None;
+            return Ok(());
+        }
+
+        if self.autooff.tick(now, self.out) {
+            cmds.push(Command::Out { ch, on: false })?;
+            self.out = None;
+            self.ramp = None;
+        }
+
+        match self.ramp.as_mut() {
+            Some(r) => {
+                match r.step(now) {
+                    Some(v) => cmds.push(Command::Vset { ch, val: v })?,
+                    None => (),
+                }
+                if r.is_done() {
+                    self.ramp = None;
+                }
             }
-        });
+            None => (),
+        }
+
+        Ok(())
+    }
+
+    /// Switch output on, ramp VSET up if soft-start is enabled
+    pub fn output_on(&mut self, ch: Channel, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        match self.vset.filter(|_| self.softstart.enabled) {
+            Some(target) => {
+                let ramp = Ramp::new(&self.softstart, target);
+                cmds.push(Command::Vset {
+                    ch,
+                    val: ramp.start_v(),
+                })?;
+                self.ramp = Some(ramp);
+            }
+            None => (),
+        }
+
+        cmds.push(Command::Out { ch, on: true })
+    }
+
+    pub fn output_off(&mut self, ch: Channel, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        // don't leave VSET half way up
+        match self.ramp.take() {
+            Some(r) => cmds.push(Command::Vset {
+                ch,
+                val: r.target(),
+            })?,
+            None => (),
+        }
+
+        cmds.push(Command::Out { ch, on: false })
+    }
+
+    /// Queued output switching (sequencer, session restore) ramps like `output_on`,
+    /// the output is taken to be switched until it's polled so a repeated on doesn't ramp again
+    pub fn check_queued_out(
+        &mut self,
+        ch: Channel,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        match cmds.out(ch) {
+            Some(true) => {
+                if self.softstart.enabled && self.ramp.is_none() && self.out != Some(true) {
+                    self.vset = cmds.vset(ch).or(self.vset);
+                    cmds.retain(|c| match c {
+                        Command::Out { ch: c, .. } => *c != ch,
+                        _ => true,
+                    });
+                    self.output_on(ch, cmds)?;
+                }
+                self.out = Some(true);
+                Ok(())
+            }
+            Some(false) => self.switched_off(ch, cmds),
+            None => Ok(()),
+        }
+    }
+
+    /// A statement of a raw instrument line for this channel, false if it's an output-on
+    /// that's ramped (queued) instead of sent
+    fn check_raw_stmt(
+        &mut self,
+        ch: Channel,
+        hdr: &str,
+        val: Option<&str>,
+        cmds: &mut CommandQueue,
+    ) -> Result<bool, AppError> {
+        if hdr.eq_ignore_ascii_case("VSET") {
+            match val.and_then(|v| v.parse::<f32>().ok()) {
+                Some(v) => {
+                    self.vset = Some(v);
+                    self.ramp.as_mut().map(|r| r.retarget(v));
+                }
+                None => (),
+            }
+            return Ok(true);
+        }
+        if !hdr.eq_ignore_ascii_case("OUT") {
+            return Ok(true);
+        }
+
+        match val {
+            Some(v) if v == "1" || v.eq_ignore_ascii_case("ON") => {
+                let ramped =
+                    self.softstart.enabled && self.ramp.is_none() && self.out != Some(true);
+                if ramped {
+                    self.output_on(ch, cmds)?;
+                }
+                self.out = Some(true);
+                Ok(!ramped)
+            }
+            Some(v) if v == "0" || v.eq_ignore_ascii_case("OFF") => {
+                self.switched_off(ch, cmds)?;
+                Ok(true)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Switched off by other means than `output_off`
+    fn switched_off(&mut self, ch: Channel, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        // don't leave VSET half way up, unless it's set anyway
+        match self.ramp.take() {
+            Some(r) if cmds.vset(ch).is_none() => cmds.push(Command::Vset {
+                ch,
+                val: r.target(),
+            })?,
+            _ => (),
+        }
+        self.out = Some(false);
+        Ok(())
     }
+}
 
-    /// Saved power samples
-    pub fn power_samples_itr(&self) -> impl Iterator<Item = &f32> {
-        self.power_samples[self.power_sample_idx..]
-            .iter()
-            .chain(self.power_samples[0..self.power_sample_idx].iter())
+/// Output-on statements of a raw instrument line (project file, script, USB) for channels
+/// with soft-start are taken out of it and ramped instead (queued), the rest of the line
+/// goes to `rest`. False if the line is to be sent as it is.
+pub fn soft_start_line<S>(
+    line: &[u8],
+    ch1: &mut PSChannel,
+    ch2: &mut PSChannel,
+    cmds: &mut CommandQueue,
+    rest: &mut String<S>,
+) -> Result<bool, AppError>
+where
+    S: ArrayLength<u8>,
+{
+    let line = core::str::from_utf8(line)
+        .map_err(|_| AppError::ProjectFileError)?
+        .trim();
+    if line.starts_with('#') || line.starts_with("++") {
+        return Ok(false); // comments and serial adapter commands
     }
 
-    /// Saved power samples relative to max power (0 to 1)
-    pub fn relative_power_samples_itr(&self) -> Option<impl Iterator<Item = f32> + '_> {
-        self.max_pout()
-            .map(move |maxp| self.power_samples_itr().map(move |p| p / maxp))
+    let mut changed = false;
+    for stmt in line.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let mut args = stmt
+            .split(|c: char| c.is_ascii_whitespace() || c == ',')
+            .filter(|a| !a.is_empty());
+        let hdr = args.next().unwrap_or("");
+        let psch = match args.next() {
+            Some("1") => Some((Channel::Ch1, &mut *ch1)),
+            Some("2") => Some((Channel::Ch2, &mut *ch2)),
+            _ => None,
+        };
+        let kept = match psch {
+            Some((ch, psch)) => psch.check_raw_stmt(ch, hdr, args.next(), cmds)?,
+            None => true,
+        };
+
+        if kept {
+            if !rest.is_empty() {
+                rest.push(';').map_err(|_| AppError::ProjectFileError)?;
+            }
+            rest.push_str(stmt)
+                .map_err(|_| AppError::ProjectFileError)?;
+        } else {
+            changed = true;
+        }
     }
+    Ok(changed)
 }
 
 pub struct UIChannel {
@@ -104,74 +346,62 @@ impl UIChannel {
         }
     }
 
-    pub fn fix_range(&mut self) {
-        self.vset = self.vset.min(20.0).max(0.0);
+    pub fn fix_range(&mut self, lim: &SoftLimits) {
+        self.vset = self.vset.min(V_MAX).min(lim.v_max).max(0.0);
 
         self.iset = self
             .iset
-            .min(if self.vset > 7.0 { 4.0 } else { 10.0 })
+            .min(if self.vset > 7.0 { 4.0 } else { I_MAX })
+            .min(lim.i_max)
             .max(0.0);
     }
 }
 
 /// Keep channel state while rotary encoder is turning,
-/// clear it out after a timeout and use query output.
+/// clear it out after a timeout (`ui_timeout_ms` in the config) and use query output.
 /// (set/query turnaround is slow over serial link)
 pub struct UIChannels {
     pub ch1: UIChannel,
     pub ch2: UIChannel,
-    last_change: Instant,
+    last_change: Millis,
 }
 
 impl UIChannels {
     #[inline]
-    pub fn fix_range(&mut self) {
-        self.ch1.fix_range();
-        self.ch2.fix_range();
+    pub fn fix_range(&mut self, l1: &SoftLimits, l2: &SoftLimits) {
+        self.ch1.fix_range(l1);
+        self.ch2.fix_range(l2);
     }
 
     #[inline]
-    fn iset_cmds<S>(&self, cmdbuf: &mut String<S>) -> Result<(), AppError>
-    where
-        S: ArrayLength<u8>,
-    {
-        Command::Iset {
+    fn iset_cmds(&self, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        cmds.push(Command::Iset {
             ch: Channel::Ch1,
             val: self.ch1.iset,
-        }
-        .append_to_str(cmdbuf)?;
+        })?;
 
-        Command::Iset {
+        cmds.push(Command::Iset {
             ch: Channel::Ch2,
             val: self.ch2.iset,
-        }
-        .append_to_str(cmdbuf)?;
-
-        Ok(())
+        })
     }
 
     #[inline]
-    fn vset_cmds<S>(&self, cmdbuf: &mut String<S>) -> Result<(), AppError>
-    where
-        S: ArrayLength<u8>,
-    {
-        Command::Vset {
+    fn vset_cmds(&self, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        cmds.push(Command::Vset {
             ch: Channel::Ch1,
             val: self.ch1.vset,
-        }
-        .append_to_str(cmdbuf)?;
+        })?;
 
-        Command::Vset {
+        cmds.push(Command::Vset {
             ch: Channel::Ch2,
             val: self.ch2.vset,
-        }
-        .append_to_str(cmdbuf)?;
-        Ok(())
+        })
     }
 }
 
 /// What changes when we turn rotary encoder
-#[derive(Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq)]
 pub enum ChSelected {
     Both,
     Ch1,
@@ -192,6 +422,7 @@ impl ChSelected {
 }
 
 /// What's being modified
+#[derive(Copy, Clone, Eq, PartialEq)]
 pub enum VarSelected {
     V,
     I,
@@ -209,8 +440,6 @@ impl VarSelected {
 // Regular info screen, show current values
 pub struct InfoScreen {
     pub selected: ChSelected,
-    pub ch1: PSChannel,
-    pub ch2: PSChannel,
     pub uich: Option<UIChannels>,
     pub vsel: VarSelected,
     pub chsel: ChSelected,
@@ -221,71 +450,25 @@ impl InfoScreen {
     pub fn new() -> Self {
         InfoScreen {
             selected: ChSelected::Both,
-            ch1: PSChannel::new(),
-            ch2: PSChannel::new(),
             uich: None,
             vsel: VarSelected::V,
             chsel: ChSelected::Both,
         }
     }
 
-    /// Handle "on/off" button (try to flip both channels at about the same time)
-    #[inline]
-    pub fn handle_on_off_button<S>(&mut self, cmdbuf: &mut String<S>) -> Result<(), AppError>
-    where
-        S: ArrayLength<u8>,
-    {
-        match self.has_output() {
-            Some(ha) => {
-                if ha {
-                    (Command::Out {
-                        ch: Channel::Ch1,
-                        on: false,
-                    })
-                    .append_to_str(cmdbuf)?;
-
-                    (Command::Out {
-                        ch: Channel::Ch2,
-                        on: false,
-                    })
-                    .append_to_str(cmdbuf)?;
-                } else {
-                    (Command::Out {
-                        ch: Channel::Ch1,
-                        on: true,
-                    })
-                    .append_to_str(cmdbuf)?;
-
-                    (Command::Out {
-                        ch: Channel::Ch2,
-                        on: true,
-                    })
-                    .append_to_str(cmdbuf)?;
-                }
-
-                // clear out, wait for next poll
-                self.ch1.out = None;
-                self.ch2.out = None;
-            }
-            None => (),
-        }
-
-        Ok(())
-    }
-
-    pub fn handle_rotary_encoder<S>(
+    pub fn handle_rotary_encoder(
         &mut self,
+        now: Millis,
+        ch1: &PSChannel,
+        ch2: &PSChannel,
         re_press_duration: Option<MilliSeconds>,
+        select_press: MilliSeconds,
         re_pressed: bool,
         re_diff: i16,
-        cmdbuf: &mut String<S>,
-    ) -> Result<(), AppError>
-    where
-        S: ArrayLength<u8>,
-    {
-        let now = Instant::now();
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
         if re_diff != 0 {
-            let mut uich: Option<UIChannels> = self.uich.take().or(self.mk_ui_channels());
+            let mut uich: Option<UIChannels> = self.uich.take().or(mk_ui_channels(ch1, ch2, now));
 
             uich.as_mut()
                 .map(|ch| {
@@ -308,9 +491,8 @@ impl InfoScreen {
                                 }
                             }
 
-                            ch.fix_range();
-                            cmdbuf.clear(); // replace previous command
-                            ch.vset_cmds(cmdbuf)
+                            ch.fix_range(&ch1.limits, &ch2.limits);
+                            ch.vset_cmds(cmds)
                         }
                         VarSelected::I => {
                             match self.chsel {
@@ -326,9 +508,8 @@ impl InfoScreen {
                                 }
                             }
 
-                            ch.fix_range();
-                            cmdbuf.clear(); // replace previous command
-                            ch.iset_cmds(cmdbuf)
+                            ch.fix_range(&ch1.limits, &ch2.limits);
+                            ch.iset_cmds(cmds)
                         }
                     }
                 })
@@ -337,7 +518,7 @@ impl InfoScreen {
         } else {
             match re_press_duration {
                 Some(rpd) => {
-                    if rpd > MilliSeconds(200) {
+                    if rpd > select_press {
                         self.vsel = self.vsel.next();
                     } else {
                         self.chsel = self.chsel.next();
@@ -350,30 +531,12 @@ impl InfoScreen {
         Ok(())
     }
 
-    fn mk_ui_channels(&self) -> Option<UIChannels> {
-        let now = Instant::now();
-        (self.ch1.vset.as_ref().zip(self.ch1.iset.as_ref()))
-            .zip(self.ch2.vset.as_ref().zip(self.ch2.iset.as_ref()))
-            .map(|((vset1, iset1), (vset2, iset2))| UIChannels {
-                ch1: UIChannel::new(*vset1, *iset1),
-                ch2: UIChannel::new(*vset2, *iset2),
-                last_change: now,
-            })
-    }
-
+    /// Drop UI values after a timeout, query results take over
     #[inline]
-    pub fn set_query_result<S>(&mut self, q: &Query, s: &String<S>) -> Result<(), AppError>
-    where
-        S: ArrayLength<u8>,
-    {
-        let now = Instant::now();
-
+    pub fn expire_ui_channels(&mut self, now: Millis, timeout_ms: u64) {
         match self.uich.take() {
             Some(ch) => {
-                // note: duration_since blows up after overflow
-                if now > ch.last_change
-                    && (now.duration_since(ch.last_change).as_cycles() < 3 * SYS_FREQ.0)
-                {
+                if now - ch.last_change < timeout_ms {
                     self.uich = Some(ch); // keep it
                 } else {
                     self.uich = None; // timed out, reset from query values
@@ -381,64 +544,441 @@ impl InfoScreen {
             }
             None => {}
         }
+    }
+}
 
-        match q.channel {
-            Channel::Ch1 => self.ch1.set_query_result(q, s),
-            Channel::Ch2 => self.ch2.set_query_result(q, s),
+fn mk_ui_channels(ch1: &PSChannel, ch2: &PSChannel, now: Millis) -> Option<UIChannels> {
+    (ch1.vset.as_ref().zip(ch1.iset.as_ref()))
+        .zip(ch2.vset.as_ref().zip(ch2.iset.as_ref()))
+        .map(|((vset1, iset1), (vset2, iset2))| UIChannels {
+            ch1: UIChannel::new(*vset1, *iset1),
+            ch2: UIChannel::new(*vset2, *iset2),
+            last_change: now,
+        })
+}
+
+/// Other screens, reached from the info screen
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum MenuItem {
+    Stats,
+    Graph(Channel),
+    Waveform,
+    Charger,
+    Sweep,
+    Calibration,
+    Settings,
+    Snapshot,
+}
+
+pub const MENU_ITEMS: [MenuItem; 9] = [
+    MenuItem::Stats,
+    MenuItem::Graph(Channel::Ch1),
+    MenuItem::Graph(Channel::Ch2),
+    MenuItem::Waveform,
+    MenuItem::Charger,
+    MenuItem::Sweep,
+    MenuItem::Calibration,
+    MenuItem::Settings,
+    MenuItem::Snapshot,
+];
+
+impl MenuItem {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            MenuItem::Stats => "Stats",
+            MenuItem::Graph(Channel::Ch1) => "Graph CH1",
+            MenuItem::Graph(Channel::Ch2) => "Graph CH2",
+            MenuItem::Waveform => "Waveform",
+            MenuItem::Charger => "Charger",
+            MenuItem::Sweep => "I-V sweep",
+            MenuItem::Calibration => "Calibrate",
+            MenuItem::Settings => "Settings",
+            MenuItem::Snapshot => "Snapshot",
+        }
+    }
+}
+
+pub struct MenuScreen {
+    pub selected: usize,
+}
+
+impl MenuScreen {
+    pub fn new() -> Self {
+        MenuScreen { selected: 0 }
+    }
+
+    pub fn handle_rotary_encoder(
+        &mut self,
+        re_press_duration: Option<MilliSeconds>,
+        short_press: MilliSeconds,
+        re_diff: i16,
+    ) -> Option<MenuItem> {
+        self.selected = (self.selected as i16 + re_diff)
+            .max(0)
+            .min(MENU_ITEMS.len() as i16 - 1) as usize;
+        re_press_duration
+            .filter(|pd| pd > &short_press)
+            .map(|_| MENU_ITEMS[self.selected])
+    }
+}
+
+/// Full screen history graph of a single channel
+pub struct GraphScreen {
+    pub ch: Channel,
+    pub quantity: Quantity,
+    pub span: Span,
+    pub cursor: usize, // history point, 0 is the oldest
+}
+
+impl GraphScreen {
+    pub fn new(ch: Channel) -> Self {
+        GraphScreen {
+            ch,
+            quantity: Quantity::V,
+            span: Span::S10,
+            cursor: HISTORY_LEN - 1,
         }
     }
 
+    pub fn handle_rotary_encoder(
+        &mut self,
+        re_press_duration: Option<MilliSeconds>,
+        select_press: MilliSeconds,
+        re_diff: i16,
+    ) {
+        self.cursor = (self.cursor as i16 + re_diff)
+            .max(0)
+            .min(HISTORY_LEN as i16 - 1) as usize;
+
+        match re_press_duration {
+            Some(rpd) => {
+                if rpd > select_press {
+                    self.span = self.span.next();
+                } else {
+                    self.quantity = self.quantity.next();
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Cursor position, milliseconds before the latest point
     #[inline]
-    pub fn has_output(&self) -> Option<bool> {
-        self.ch1.out.zip(self.ch2.out).map(|(e1, e2)| e1 || e2)
+    pub fn cursor_age_ms(&self) -> u32 {
+        (HISTORY_LEN - 1 - self.cursor) as u32 * self.span.sample_period_ms()
     }
 }
 
-/// List SD card root dir, load file
+/// Entry that goes back to the parent directory
+pub const PARENT_DIR: &str = "..";
+
+/// List SD card dir, load file
 pub struct ProjectFiles {
+    pub dir: String<U64>, // path from the root, empty in the root
+    exts: Vec<String<U3>, U4>,
     pub fnames: Vec<String<U32>, U64>,
+    pub titles: Vec<Option<String<U32>>, U64>, // from the file headers, same order as fnames
     pub selected: usize,
 }
 
 impl ProjectFiles {
-    pub fn new(sdc: &mut SDCard) -> Result<Self, AppError> {
-        let mut fnames = Vec::new();
-        sdc.list_projects_files(&mut fnames)?;
+    /// Files (with one of `exts`, any if empty) in a directory, the root if it's gone
+    pub fn new(sdc: &mut SDCard, dir: &str, exts: &[String<U3>]) -> Result<Self, AppError> {
+        let mut pfs = ProjectFiles {
+            dir: String::new(),
+            exts: Vec::from_slice(exts).map_err(|_| AppError::Duh)?,
+            fnames: Vec::new(),
+            titles: Vec::new(),
+            selected: 0,
+        };
+
+        if pfs.dir.push_str(dir).is_err() || pfs.list(sdc).is_err() {
+            pfs.dir.clear();
+            pfs.list(sdc)?;
+        }
 
-        if fnames.is_empty() {
+        if pfs.fnames.is_empty() {
             Err(AppError::ProjectFileError)
         } else {
-            Ok(ProjectFiles {
-                fnames,
-                selected: 0,
-            })
+            Ok(pfs)
+        }
+    }
+
+    fn list(&mut self, sdc: &mut SDCard) -> Result<(), AppError> {
+        self.fnames.clear();
+        self.selected = 0;
+        if !self.dir.is_empty() {
+            self.fnames
+                .push(String::from(PARENT_DIR))
+                .map_err(|_| AppError::Duh)?;
         }
+        sdc.list_projects_files(&self.dir, &self.exts, &mut self.fnames)?;
+
+        self.titles.clear();
+        for name in self.fnames.iter() {
+            let title = if name.as_str() == PARENT_DIR || name.ends_with(DIR_MARKER) {
+                None
+            } else {
+                file_path(&self.dir, name)
+                    .ok()
+                    .and_then(|path| header_title(sdc, &path))
+            };
+            self.titles.push(title).map_err(|_| AppError::Duh)?;
+        }
+        Ok(())
+    }
+
+    /// Title from the file header, or the name
+    pub fn label(&self, i: usize) -> &str {
+        match &self.titles[i] {
+            Some(title) => title,
+            None => &self.fnames[i],
+        }
+    }
+
+    /// Directories are entered (or left) on a press, path of a file to load is returned
+    pub fn handle_rotary_encoder(
+        &mut self,
+        sdc: &mut SDCard,
+        re_press_duration: Option<MilliSeconds>,
+        short_press: MilliSeconds,
+        re_diff: i16,
+    ) -> Result<Option<String<U64>>, AppError> {
+        self.selected = ((self.selected as i16 + re_diff).max(0) as usize)
+            .min(self.fnames.len().saturating_sub(1));
+
+        if re_press_duration.filter(|pd| pd > &short_press).is_none() {
+            return Ok(None);
+        }
+
+        let name = self.fnames[self.selected].clone();
+        if name.as_str() == PARENT_DIR {
+            let parent_len = split_path(&self.dir).0.len();
+            self.dir.truncate(parent_len);
+            self.list(sdc)?;
+            Ok(None)
+        } else if name.ends_with(DIR_MARKER) {
+            if !self.dir.is_empty() {
+                self.dir.push('/').map_err(|_| AppError::ProjectFileError)?;
+            }
+            self.dir
+                .push_str(name.trim_end_matches(DIR_MARKER))
+                .map_err(|_| AppError::ProjectFileError)?;
+            self.list(sdc)?;
+            Ok(None)
+        } else {
+            file_path(&self.dir, &name).map(Some)
+        }
+    }
+}
+
+/// Path of a file in a directory
+fn file_path(dir: &str, name: &str) -> Result<String<U64>, AppError> {
+    let mut path = String::from(dir);
+    if !path.is_empty() {
+        path.push('/').map_err(|_| AppError::ProjectFileError)?;
     }
+    path.push_str(name)
+        .map_err(|_| AppError::ProjectFileError)?;
+    Ok(path)
+}
+
+/// Title in a file header, reads no further than the header
+fn header_title(sdc: &mut SDCard, path: &str) -> Option<String<U32>> {
+    let mut meta = FileHeader::new();
+    let mut line_no = 0u16;
+    sdc.read_lines_while(path, |line| {
+        line_no = line_no.saturating_add(1);
+        Ok(meta.parse_line(line_no, line))
+    })
+    .ok()?;
+    meta.title
+}
+
+/// Lines shown in the preview
+const PREVIEW_LINES: usize = 5;
+
+/// Selected file before it's run: its metadata header, comment header (leading '#' lines)
+/// or first lines, size and date, whether its declared limits are the current ones,
+/// run or cancel
+pub struct FilePreview {
+    pub path: String<U64>,
+    pub info: FileInfo,
+    pub meta: FileHeader,
+    pub lines: Vec<String<U32>, U5>,
+    pub header: bool,    // lines are from the comment header
+    pub limits_ok: bool, // declared limits match the current ones
+    pub run: bool,       // answer, cancel to begin with
+}
+
+impl FilePreview {
+    pub fn new(
+        sdc: &mut SDCard,
+        path: String<U64>,
+        l1: &SoftLimits,
+        l2: &SoftLimits,
+    ) -> Result<Self, AppError> {
+        let info = sdc.file_info(&path)?;
+
+        let mut meta = FileHeader::new();
+        let mut header: Vec<String<U32>, U5> = Vec::new();
+        let mut first: Vec<String<U32>, U5> = Vec::new();
+        let mut in_header = true;
+        let mut line_no = 0u16;
+        sdc.read_lines_while(&path, |line| {
+            line_no = line_no.saturating_add(1);
+            in_header = in_header && meta.parse_line(line_no, line);
+
+            let line = core::str::from_utf8(line)
+                .map_err(|_| AppError::ProjectFileError)?
+                .trim();
+            if line.is_empty() {
+                return Ok(true);
+            }
+
+            if in_header && header.len() < PREVIEW_LINES {
+                header
+                    .push(truncated(line.trim_start_matches('#').trim()))
+                    .ok();
+            }
+            if first.len() < PREVIEW_LINES {
+                first.push(truncated(line)).ok();
+            }
+            Ok(in_header || first.len() < PREVIEW_LINES)
+        })?;
 
+        let has_header = !header.is_empty();
+        let lines = if !meta.is_empty() {
+            meta_lines(&meta)
+        } else if has_header {
+            header
+        } else {
+            first
+        };
+        let limits_ok = meta.limits_match(l1, l2);
+        Ok(FilePreview {
+            path,
+            info,
+            meta,
+            lines,
+            header: has_header,
+            limits_ok,
+            run: false,
+        })
+    }
+
+    /// Encoder picks run/cancel, Some(run) once pressed
     pub fn handle_rotary_encoder(
         &mut self,
         re_press_duration: Option<MilliSeconds>,
+        short_press: MilliSeconds,
         re_diff: i16,
-    ) -> Result<Option<String<U32>>, AppError> {
-        self.selected = ((self.selected as i16 + re_diff).max(0) as usize).min(self.fnames.len());
-        Ok(re_press_duration
-            .filter(|pd| pd > &MilliSeconds(100))
-            .map(|_| self.fnames[self.selected].clone()))
+    ) -> Option<bool> {
+        if re_diff != 0 {
+            self.run = re_diff > 0;
+        }
+        re_press_duration
+            .filter(|pd| pd > &short_press)
+            .map(|_| self.run)
     }
 }
 
+/// Metadata header as preview lines, as many as fit
+fn meta_lines(meta: &FileHeader) -> Vec<String<U32>, U5> {
+    let mut lines: Vec<String<U32>, U5> = Vec::new();
+    if let Some(title) = &meta.title {
+        lines.push(title.clone()).ok();
+    }
+    if let Some(line_no) = meta.error_line {
+        let mut s: String<U32> = String::new();
+        write!(s, "bad header line {}", line_no).ok();
+        lines.push(s).ok();
+    }
+    for d in meta.description.iter() {
+        lines.push(d.clone()).ok();
+    }
+    if !meta.channels.is_empty() {
+        let mut s: String<U32> = String::from("ch");
+        for ch in meta.channels.iter() {
+            write!(s, " {}", ch.to_str()).ok();
+        }
+        lines.push(s).ok();
+    }
+    for (ch, l) in [Channel::Ch1, Channel::Ch2].iter().zip(meta.limits.iter()) {
+        if let Some((_, l)) = l {
+            let mut s: String<U32> = String::new();
+            write!(s, "limit {} {:.3}V {:.3}A", ch.to_str(), l.v_max, l.i_max).ok();
+            lines.push(s).ok();
+        }
+    }
+    lines
+}
+
 /// UI states
 pub enum UI {
     UILoading(&'static str),
     USSBSerial,
     InfoScreen(InfoScreen),
+    MenuScreen(MenuScreen),
+    StatsScreen,
+    GraphScreen(GraphScreen),
+    SettingsScreen(SettingsScreen),
+    SequencerScreen(Sequencer),
+    ScriptScreen(ScriptRun),
+    WaveformScreen(WaveformScreen),
+    ChargerScreen,
+    SweepScreen(SweepScreen),
+    CalScreen(CalScreen),
+    SaveScreen(SaveScreen),
+    RestoreScreen(RestoreScreen),
     ProjectFiles(ProjectFiles),
+    FilePreview(FilePreview, ProjectFiles), // the list to go back to
+}
+
+/// What the rest of a project file turned into
+pub enum Program {
+    Sequence(Sequence),
+    Script(Script),
+}
+
+impl Program {
+    #[inline]
+    pub fn parse_line(&mut self, line_no: u16, line: &[u8]) -> Result<(), AppError> {
+        match self {
+            Program::Sequence(sq) => sq.parse_line(line),
+            Program::Script(sc) => sc.parse_line(line_no, line),
+        }
+    }
+
+    #[inline]
+    pub fn validate(&self) -> Result<(), AppError> {
+        match self {
+            Program::Sequence(sq) => sq.validate(),
+            Program::Script(sc) => sc.validate(),
+        }
+    }
 }
 
 /// State of the power supply controller
 pub struct PS {
     pub error: Option<AppError>,
+    pub error_at: Option<(String<U32>, u16)>, // project file and line the error came from
     pub ui: UI,
+    pub ch1: PSChannel,
+    pub ch2: PSChannel,
+    pub commands: CommandQueue,
+    pub waveform: Waveform,
+    pub charger: Option<Charger>,
+    pub sweep: SweepParams,
+    pub link: Link,
+    pub datalog: DataLog,
+    pub config: Config,
+    pub wall_time: Option<DateTime>,    // RTC, if it's set
+    pub card_present: bool,             // SD card, as of the last access or check
+    pub session: SessionTracker,        // last session, saved to the SD card
+    pub loaded_mode: Option<MenuItem>,  // screen to open after loading a project file
+    pub project_dir: String<U64>,       // last directory of the file selector
+    pub file_exts: Vec<String<U3>, U4>, // file selector only lists these (all if empty)
 }
 
 impl PS {
@@ -446,10 +986,32 @@ impl PS {
     pub fn new() -> Self {
         PS {
             error: None,
+            error_at: None,
             ui: UI::UILoading("Initializing..."),
+            ch1: PSChannel::new(),
+            ch2: PSChannel::new(),
+            commands: CommandQueue::new(),
+            waveform: Waveform::new(),
+            charger: None,
+            sweep: SweepParams::new(),
+            link: Link::new(),
+            datalog: DataLog::new(),
+            config: Config::new(),
+            wall_time: None,
+            card_present: false,
+            session: SessionTracker::new(),
+            loaded_mode: None,
+            project_dir: String::new(),
+            file_exts: Vec::new(),
         }
     }
 
+    /// Settings from the config file
+    pub fn set_config(&mut self, config: Config) {
+        self.link.ping_ms = config.ping_ms();
+        self.config = config;
+    }
+
     #[inline]
     pub fn show_error(&mut self, e: AppError) {
         if self.error.is_none() {
@@ -457,9 +1019,18 @@ impl PS {
         }
     }
 
+    /// Error in a project file, with where it happened
+    pub fn show_file_error(&mut self, e: AppError, fname: &str, line_no: u16) {
+        if self.error.is_none() {
+            self.error = Some(e);
+            self.error_at = Some((truncated(split_path(fname).1), line_no));
+        }
+    }
+
     #[inline]
     pub fn clear_error(&mut self) {
-        self.error = None
+        self.error = None;
+        self.error_at = None;
     }
 
     #[inline]
@@ -476,4 +1047,576 @@ impl PS {
     pub fn set_ui_info_screen(&mut self) {
         self.ui = UI::InfoScreen(InfoScreen::new())
     }
+
+    #[inline]
+    pub fn set_ui_menu_screen(&mut self) {
+        self.ui = UI::MenuScreen(MenuScreen::new())
+    }
+
+    /// Run a sequence or a script loaded from a project file
+    pub fn set_ui_program_screen(&mut self, prog: Program) {
+        // it owns the setpoints now
+        self.ch1.ramp = None;
+        self.ch2.ramp = None;
+        self.ui = match prog {
+            Program::Sequence(seq) => UI::SequencerScreen(Sequencer::new(seq)),
+            Program::Script(sc) => UI::ScriptScreen(ScriptRun::new(sc)),
+        }
+    }
+
+    /// Screens reached from the menu
+    pub fn open_screen(&mut self, item: MenuItem) {
+        self.ui = match item {
+            MenuItem::Stats => UI::StatsScreen,
+            MenuItem::Graph(ch) => UI::GraphScreen(GraphScreen::new(ch)),
+            MenuItem::Waveform => UI::WaveformScreen(WaveformScreen::new()),
+            MenuItem::Charger => UI::ChargerScreen,
+            MenuItem::Sweep => UI::SweepScreen(SweepScreen::new()),
+            MenuItem::Calibration => UI::CalScreen(CalScreen::new()),
+            MenuItem::Settings => UI::SettingsScreen(SettingsScreen::new()),
+            MenuItem::Snapshot => UI::SaveScreen(SaveScreen::new()),
+        }
+    }
+
+    #[inline]
+    pub fn channel(&self, ch: Channel) -> &PSChannel {
+        match ch {
+            Channel::Ch1 => &self.ch1,
+            Channel::Ch2 => &self.ch2,
+        }
+    }
+
+    #[inline]
+    pub fn channel_mut(&mut self, ch: Channel) -> &mut PSChannel {
+        match ch {
+            Channel::Ch1 => &mut self.ch1,
+            Channel::Ch2 => &mut self.ch2,
+        }
+    }
+
+    /// Periodic update, driven by the monotonic clock
+    #[inline]
+    pub fn tick(&mut self, now: Millis) -> Result<(), AppError> {
+        self.ch1.tick(Channel::Ch1, now, &mut self.commands)?;
+        self.ch2.tick(Channel::Ch2, now, &mut self.commands)?;
+
+        self.datalog
+            .tick(now, self.wall_time.as_ref(), &self.ch1, &self.ch2);
+        self.session.update(now, &self.ch1, &self.ch2, &self.ui);
+
+        // keeps charging on any screen
+        match self.charger.as_mut() {
+            Some(c) => {
+                let psch = match c.ch {
+                    Channel::Ch1 => &mut self.ch1,
+                    Channel::Ch2 => &mut self.ch2,
+                };
+                c.tick(now, psch, &mut self.commands)?;
+            }
+            None => (),
+        }
+
+        match &mut self.ui {
+            UI::InfoScreen(is) => {
+                is.expire_ui_channels(now, self.config.ui_timeout_ms);
+                Ok(())
+            }
+            UI::SequencerScreen(sq) => sq.tick(now, &mut self.commands),
+            UI::ScriptScreen(sr) => {
+                let res = sr.tick(now, &self.ch1, &self.ch2);
+                if res.is_err() {
+                    sr.stop();
+                }
+                res
+            }
+            UI::SweepScreen(ss) => {
+                let psch = match ss.run.channel(&self.sweep) {
+                    Channel::Ch1 => &mut self.ch1,
+                    Channel::Ch2 => &mut self.ch2,
+                };
+                ss.run.tick(now, &mut self.sweep, psch, &mut self.commands)
+            }
+            UI::WaveformScreen(ws) => ws.gen.tick(
+                now,
+                &self.waveform,
+                &self.link,
+                &self.ch1.limits,
+                &self.ch2.limits,
+                &mut self.commands,
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Soft-start for queued output-ons, drop queued setpoints over the soft limits
+    pub fn check_commands(&mut self) -> Result<(), AppError> {
+        self.ch1
+            .check_queued_out(Channel::Ch1, &mut self.commands)?;
+        self.ch2
+            .check_queued_out(Channel::Ch2, &mut self.commands)?;
+
+        let (l1, l2) = (self.ch1.limits, self.ch2.limits);
+        let all_within = self.commands.retain(|c| match c.channel() {
+            Channel::Ch1 => l1.within(c),
+            Channel::Ch2 => l2.within(c),
+        });
+
+        if all_within {
+            Ok(())
+        } else {
+            Err(AppError::LimitError)
+        }
+    }
+
+    /// Link watchdog, safe state once the instrument answers again
+    pub fn check_link(&mut self, now: Millis) -> Result<(), AppError> {
+        match self.link.check(now) {
+            Some(LinkEvent::Lost) => {
+                self.ch1.ramp = None;
+                self.ch2.ramp = None;
+            }
+            Some(LinkEvent::Restored) => {
+                // whatever was held back is stale by now
+                self.commands.clear();
+                if self.link.safe_off {
+                    self.commands.push(Command::Out {
+                        ch: Channel::Ch1,
+                        on: false,
+                    })?;
+                    self.commands.push(Command::Out {
+                        ch: Channel::Ch2,
+                        on: false,
+                    })?;
+                }
+                // the instrument may have been power cycled
+                self.ch1.resync();
+                self.ch2.resync();
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Controller settings from a project file
+    pub fn apply_directive(&mut self, d: Directive) -> Result<(), AppError> {
+        match d {
+            Directive::SoftStart { ch, softstart } => self.channel_mut(ch).softstart = softstart,
+            Directive::EFuse { ch, efuse } => self.channel_mut(ch).efuse = efuse,
+            Directive::Limit { ch, limits } => self.channel_mut(ch).limits = limits,
+            Directive::Calibration(ch, target, cal) => {
+                *self.channel_mut(ch).cal.get_mut(target) = cal
+            }
+            Directive::Watchdog {
+                timeout_ms,
+                safe_off,
+            } => {
+                self.link.timeout_ms = timeout_ms;
+                self.link.safe_off = safe_off;
+            }
+            Directive::AutoOff { chs, ms } => {
+                if chs.is_selected(ChSelected::Ch1) {
+                    self.ch1.autooff.set(ms);
+                }
+                if chs.is_selected(ChSelected::Ch2) {
+                    self.ch2.autooff.set(ms);
+                }
+            }
+            Directive::Log(Some(interval_ms)) => {
+                self.datalog.interval_ms = interval_ms;
+                self.datalog.run();
+            }
+            Directive::Log(None) => self.datalog.stop(),
+            Directive::Files(exts) => self.file_exts = exts,
+            Directive::Sequence { .. } | Directive::Script => (), // the rest of the file is parsed by the loader
+            Directive::Time(_) => (),                             // the loader sets the RTC
+            Directive::Waveform(wf) => {
+                self.waveform = wf;
+                self.loaded_mode = Some(MenuItem::Waveform);
+            }
+            Directive::Sweep(sp) => {
+                self.sweep = sp;
+                self.loaded_mode = Some(MenuItem::Sweep);
+            }
+            Directive::Charge { ch, profile } => {
+                // don't pull the rug from under a running charge
+                if self.is_charging() {
+                    return Err(AppError::ProjectFileError);
+                }
+                self.charger = Some(Charger::new(ch, profile));
+                self.loaded_mode = Some(MenuItem::Charger);
+            }
+            Directive::Table(vals) => self
+                .waveform
+                .table
+                .extend_from_slice(&vals)
+                .map_err(|_| AppError::ProjectFileError)?,
+        }
+        Ok(())
+    }
+
+    /// Button and rotary encoder input on live screens
+    pub fn handle_input(
+        &mut self,
+        now: Millis,
+        re_diff: i16,
+        re_press_duration: Option<MilliSeconds>,
+        re_pressed: bool,
+        btn_press_duration: Option<MilliSeconds>,
+    ) -> Result<(), AppError> {
+        let cfg = self.config;
+        let btn_short_press = btn_press_duration.filter(|pd| pd > &cfg.short_press);
+        let re_very_long_press = re_press_duration.filter(|pd| pd > &MilliSeconds(1000));
+
+        match &mut self.ui {
+            UI::InfoScreen(is) => {
+                if re_very_long_press.is_some() {
+                    self.set_ui_menu_screen();
+                } else {
+                    // manual VSET change takes over from soft-start
+                    if re_diff != 0 && is.vsel == VarSelected::V {
+                        self.ch1.ramp = None;
+                        self.ch2.ramp = None;
+                    }
+
+                    is.handle_rotary_encoder(
+                        now,
+                        &self.ch1,
+                        &self.ch2,
+                        re_press_duration,
+                        cfg.select_press,
+                        re_pressed,
+                        re_diff,
+                        &mut self.commands,
+                    )?;
+                }
+
+                if btn_short_press.is_some() {
+                    if self.fuse_tripped() {
+                        // acknowledge first, outputs stay off
+                        self.ch1.fuse.acknowledge();
+                        self.ch2.fuse.acknowledge();
+                    } else {
+                        self.handle_on_off_button()?;
+                    }
+                }
+
+                Ok(())
+            }
+            UI::MenuScreen(ms) => {
+                if btn_short_press.is_some() {
+                    self.set_ui_info_screen();
+                } else {
+                    match ms.handle_rotary_encoder(re_press_duration, cfg.short_press, re_diff) {
+                        Some(item) => self.open_screen(item),
+                        None => (),
+                    }
+                }
+                Ok(())
+            }
+            UI::GraphScreen(gs) => {
+                if btn_short_press.is_some() {
+                    self.set_ui_info_screen();
+                } else {
+                    gs.handle_rotary_encoder(re_press_duration, cfg.select_press, re_diff);
+                }
+                Ok(())
+            }
+            UI::SettingsScreen(ss) => {
+                if btn_short_press.is_some() {
+                    self.set_ui_info_screen();
+                } else {
+                    match ss.handle_rotary_encoder(
+                        &SETTINGS,
+                        re_press_duration,
+                        cfg.short_press,
+                        re_pressed,
+                        re_diff,
+                    ) {
+                        Some((st, steps)) => st.adjust(self, steps),
+                        None => (),
+                    }
+                }
+                Ok(())
+            }
+            UI::SequencerScreen(sq) => {
+                if btn_short_press.is_some() {
+                    if sq.state != RunState::Done {
+                        // abort, don't leave outputs on half way through
+                        self.ch1.output_off(Channel::Ch1, &mut self.commands)?;
+                        self.ch2.output_off(Channel::Ch2, &mut self.commands)?;
+                        self.ch1.out = None;
+                        self.ch2.out = None;
+                    }
+                    self.set_ui_info_screen();
+                } else if re_press_duration.is_some() {
+                    sq.toggle_pause();
+                }
+                Ok(())
+            }
+            UI::ScriptScreen(sr) => {
+                if btn_short_press.is_some() {
+                    if sr.state != RunState::Done {
+                        sr.stop();
+                        self.ch1.output_off(Channel::Ch1, &mut self.commands)?;
+                        self.ch2.output_off(Channel::Ch2, &mut self.commands)?;
+                        self.ch1.out = None;
+                        self.ch2.out = None;
+                    }
+                    self.set_ui_info_screen();
+                } else if re_press_duration.is_some() {
+                    sr.toggle_pause();
+                }
+                Ok(())
+            }
+            UI::WaveformScreen(ws) => {
+                if btn_short_press.is_some() {
+                    self.stop_waveform()?;
+                    self.set_ui_info_screen();
+                } else {
+                    match ws.list.handle_rotary_encoder(
+                        &WAVE_SETTINGS,
+                        re_press_duration,
+                        cfg.short_press,
+                        re_pressed,
+                        re_diff,
+                    ) {
+                        Some((st, steps)) => st.adjust(self, steps),
+                        None => (),
+                    }
+                }
+                Ok(())
+            }
+            UI::SweepScreen(ss) => {
+                if btn_short_press.is_some() {
+                    self.sweep.run = false;
+                    if ss.run.state == SweepState::Running {
+                        // abort, results of a partial sweep are dropped
+                        let ch = ss.run.active.ch;
+                        self.commands.push(Command::Out { ch, on: false })?;
+                        self.channel_mut(ch).out = None;
+                    }
+                    self.set_ui_info_screen();
+                } else if ss.show_plot {
+                    if re_press_duration.is_some() {
+                        ss.show_plot = false;
+                    }
+                } else {
+                    match ss.list.handle_rotary_encoder(
+                        &SWEEP_SETTINGS,
+                        re_press_duration,
+                        cfg.short_press,
+                        re_pressed,
+                        re_diff,
+                    ) {
+                        Some((st, steps)) => {
+                            st.adjust(self, steps);
+                            match &mut self.ui {
+                                // plot the results as they come in
+                                UI::SweepScreen(ss) => ss.show_plot = self.sweep.run,
+                                _ => (),
+                            }
+                        }
+                        None => (),
+                    }
+                }
+                Ok(())
+            }
+            UI::CalScreen(cs) => {
+                if btn_short_press.is_some() {
+                    self.set_ui_info_screen();
+                } else {
+                    let psch = match cs.target().0 {
+                        Channel::Ch1 => &mut self.ch1,
+                        Channel::Ch2 => &mut self.ch2,
+                    };
+                    cs.handle_rotary_encoder(
+                        psch,
+                        re_press_duration,
+                        cfg.short_press,
+                        re_pressed,
+                        re_diff,
+                    );
+                }
+                Ok(())
+            }
+            UI::ChargerScreen => {
+                if btn_short_press.is_some() {
+                    self.set_ui_info_screen(); // charging goes on in the background
+                } else {
+                    self.handle_charger_input(re_press_duration)?;
+                }
+                Ok(())
+            }
+            UI::StatsScreen => {
+                if btn_short_press.is_some() {
+                    self.set_ui_info_screen();
+                } else if re_press_duration.is_some() {
+                    self.ch1.stats.reset();
+                    self.ch2.stats.reset();
+                }
+                Ok(())
+            }
+            UI::RestoreScreen(rs) => {
+                let answer = if btn_short_press.is_some() {
+                    Some(false)
+                } else {
+                    rs.handle_rotary_encoder(re_press_duration, cfg.short_press, re_diff)
+                };
+                let session = rs.session;
+                if answer.is_some() {
+                    self.session.enabled = true; // changes from here on are saved
+                }
+                match answer {
+                    Some(true) => self.restore_session(&session),
+                    Some(false) => {
+                        self.set_ui_info_screen();
+                        Ok(())
+                    }
+                    None => Ok(()),
+                }
+            }
+            UI::SaveScreen(ss) => {
+                if btn_short_press.is_some() {
+                    self.set_ui_info_screen();
+                } else if re_press_duration
+                    .filter(|pd| pd > &cfg.short_press)
+                    .is_some()
+                {
+                    // another file with whatever the setpoints are now
+                    ss.save_pending = true;
+                    ss.saved = None;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Setpoints of a saved session with both outputs off, then its screen
+    pub fn restore_session(&mut self, s: &Session) -> Result<(), AppError> {
+        self.ch1.output_off(Channel::Ch1, &mut self.commands)?;
+        self.ch2.output_off(Channel::Ch2, &mut self.commands)?;
+        for (i, ch) in [Channel::Ch1, Channel::Ch2].iter().enumerate() {
+            self.commands.push(Command::Vset {
+                ch: *ch,
+                val: s.vset[i],
+            })?;
+            self.commands.push(Command::Iset {
+                ch: *ch,
+                val: s.iset[i],
+            })?;
+        }
+
+        match s.screen {
+            Some(item) => self.open_screen(item),
+            None => {
+                let mut is = InfoScreen::new();
+                is.chsel = s.chsel;
+                is.vsel = s.vsel;
+                self.ui = UI::InfoScreen(is);
+            }
+        }
+        Ok(())
+    }
+
+    /// Either fuse tripped and not acknowledged yet
+    #[inline]
+    pub fn fuse_tripped(&self) -> bool {
+        self.ch1.fuse.tripped().is_some() || self.ch2.fuse.tripped().is_some()
+    }
+
+    #[inline]
+    pub fn is_charging(&self) -> bool {
+        self.charger
+            .as_ref()
+            .map_or(false, |c| c.phase.is_charging())
+    }
+
+    /// Press to start, very long press to stop, press to dismiss the summary
+    fn handle_charger_input(
+        &mut self,
+        re_press_duration: Option<MilliSeconds>,
+    ) -> Result<(), AppError> {
+        let pd = match re_press_duration {
+            Some(pd) => pd,
+            None => return Ok(()),
+        };
+
+        match self.charger.as_mut() {
+            Some(c) => {
+                let psch = match c.ch {
+                    Channel::Ch1 => &mut self.ch1,
+                    Channel::Ch2 => &mut self.ch2,
+                };
+                match c.phase {
+                    ChargePhase::Ready => c.start(psch, &mut self.commands)?,
+                    ChargePhase::CC | ChargePhase::CV => {
+                        if pd > MilliSeconds(1000) {
+                            c.stop(psch, &mut self.commands, ChargeEnd::Aborted)?
+                        }
+                    }
+                    ChargePhase::Done(_) => {
+                        self.charger = None;
+                        self.set_ui_info_screen();
+                    }
+                }
+            }
+            None => (),
+        }
+
+        Ok(())
+    }
+
+    /// Stop the function generator, leave its output at the offset
+    pub fn stop_waveform(&mut self) -> Result<(), AppError> {
+        self.waveform.running = false;
+        match &mut self.ui {
+            UI::WaveformScreen(ws) => ws.gen.stop(
+                &self.waveform,
+                &self.ch1.limits,
+                &self.ch2.limits,
+                &mut self.commands,
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Handle "on/off" button (try to flip both channels at about the same time)
+    #[inline]
+    pub fn handle_on_off_button(&mut self) -> Result<(), AppError> {
+        match self.has_output() {
+            Some(ha) => {
+                if ha {
+                    self.ch1.output_off(Channel::Ch1, &mut self.commands)?;
+                    self.ch2.output_off(Channel::Ch2, &mut self.commands)?;
+                } else {
+                    self.ch1.output_on(Channel::Ch1, &mut self.commands)?;
+                    self.ch2.output_on(Channel::Ch2, &mut self.commands)?;
+                }
+
+                // clear out, wait for next poll
+                self.ch1.out = None;
+                self.ch2.out = None;
+            }
+            None => (),
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_query_result<S>(&mut self, q: &Query, s: &String<S>) -> Result<(), AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        match q.channel {
+            Channel::Ch1 => self.ch1.set_query_result(q, s),
+            Channel::Ch2 => self.ch2.set_query_result(q, s),
+        }
+    }
+
+    #[inline]
+    pub fn has_output(&self) -> Option<bool> {
+        self.ch1.out.zip(self.ch2.out).map(|(e1, e2)| e1 || e2)
+    }
 }
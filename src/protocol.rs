@@ -2,11 +2,11 @@
 
 use core::fmt::Write;
 
-use heapless::{consts::*, ArrayLength, String};
+use heapless::{consts::*, ArrayLength, String, Vec};
 
 use crate::prelude::AppError;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Channel {
     Ch1,
     Ch2,
@@ -19,15 +19,68 @@ impl Channel {
             Channel::Ch2 => "2",
         }
     }
+
+    pub fn parse(s: &str) -> Result<Self, AppError> {
+        match s {
+            "1" => Ok(Channel::Ch1),
+            "2" => Ok(Channel::Ch2),
+            _ => Err(AppError::ParseError),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub enum ChannelHeader {
     Vset,
     Iset,
+    Ovset,
     Vout,
     Iout,
     Out,
+    Sts,
+}
+
+/// Channel status register (STS? query)
+#[derive(Copy, Clone, Debug)]
+pub struct Status(pub u16);
+
+impl Status {
+    pub const CV: u16 = 1; // constant voltage
+    pub const CC_POS: u16 = 2; // constant current, positive
+    pub const CC_NEG: u16 = 4; // constant current, negative
+    pub const OV: u16 = 8; // overvoltage protection tripped
+    pub const OT: u16 = 16; // overtemperature protection tripped
+    pub const OC: u16 = 64; // overcurrent protection tripped
+
+    #[inline]
+    fn is_set(&self, bits: u16) -> bool {
+        self.0 & bits != 0
+    }
+
+    #[inline]
+    pub fn is_cv(&self) -> bool {
+        self.is_set(Status::CV)
+    }
+
+    #[inline]
+    pub fn is_cc(&self) -> bool {
+        self.is_set(Status::CC_POS | Status::CC_NEG)
+    }
+
+    #[inline]
+    pub fn is_ov(&self) -> bool {
+        self.is_set(Status::OV)
+    }
+
+    #[inline]
+    pub fn is_ot(&self) -> bool {
+        self.is_set(Status::OT)
+    }
+
+    #[inline]
+    pub fn is_oc(&self) -> bool {
+        self.is_set(Status::OC)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -52,6 +105,94 @@ impl Command {
 
         Ok(())
     }
+
+    pub fn channel(&self) -> Channel {
+        match self {
+            Command::Vset { ch, .. } | Command::Iset { ch, .. } | Command::Out { ch, .. } => *ch,
+        }
+    }
+
+    /// Same setting of the same channel (a newer command overrides an older one)
+    pub fn same_target(&self, other: &Command) -> bool {
+        match (self, other) {
+            (Command::Vset { ch: c1, .. }, Command::Vset { ch: c2, .. }) => c1 == c2,
+            (Command::Iset { ch: c1, .. }, Command::Iset { ch: c2, .. }) => c1 == c2,
+            (Command::Out { ch: c1, .. }, Command::Out { ch: c2, .. }) => c1 == c2,
+            _ => false,
+        }
+    }
+}
+
+/// Commands waiting to be sent, all setpoints are absolute values
+/// so only the latest command for each setting is kept
+pub struct CommandQueue {
+    cmds: Vec<Command, U16>,
+}
+
+impl CommandQueue {
+    pub fn new() -> Self {
+        CommandQueue { cmds: Vec::new() }
+    }
+
+    pub fn push(&mut self, cmd: Command) -> Result<(), AppError> {
+        match self.cmds.iter_mut().find(|c| c.same_target(&cmd)) {
+            Some(c) => *c = cmd,
+            None => self.cmds.push(cmd).map_err(|_| AppError::Duh)?,
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    /// Queued output switching of a channel
+    pub fn out(&self, ch: Channel) -> Option<bool> {
+        self.cmds.iter().find_map(|c| match c {
+            Command::Out { ch: c, on } if *c == ch => Some(*on),
+            _ => None,
+        })
+    }
+
+    /// Queued VSET of a channel
+    pub fn vset(&self, ch: Channel) -> Option<f32> {
+        self.cmds.iter().find_map(|c| match c {
+            Command::Vset { ch: c, val } if *c == ch => Some(*val),
+            _ => None,
+        })
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.cmds.clear()
+    }
+
+    /// Drop commands `keep` says no to, false if any were dropped
+    pub fn retain<F>(&mut self, mut keep: F) -> bool
+    where
+        F: FnMut(&Command) -> bool,
+    {
+        let n = self.cmds.len();
+        let mut kept: Vec<Command, U16> = Vec::new();
+        for c in self.cmds.iter().filter(|c| keep(c)) {
+            kept.push(*c).ok(); // can't be more than there was
+        }
+        self.cmds = kept;
+        self.cmds.len() == n
+    }
+
+    /// All queued commands as a single line, empties the queue
+    pub fn drain_to_str<S>(&mut self, buf: &mut String<S>) -> Result<(), AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        for c in self.cmds.iter() {
+            c.append_to_str(buf)?;
+        }
+        self.cmds.clear();
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -60,7 +201,7 @@ pub struct Query {
     pub channel: Channel,
 }
 
-pub const QUERY_PING_LOOP: [Query; 18] = [
+pub const QUERY_PING_LOOP: [Query; 22] = [
     Query {
         header: ChannelHeader::Vset,
         channel: Channel::Ch1,
@@ -109,6 +250,14 @@ pub const QUERY_PING_LOOP: [Query; 18] = [
         header: ChannelHeader::Iout,
         channel: Channel::Ch2,
     },
+    Query {
+        header: ChannelHeader::Ovset,
+        channel: Channel::Ch1,
+    },
+    Query {
+        header: ChannelHeader::Ovset,
+        channel: Channel::Ch2,
+    },
     Query {
         header: ChannelHeader::Out,
         channel: Channel::Ch1,
@@ -117,6 +266,14 @@ pub const QUERY_PING_LOOP: [Query; 18] = [
         header: ChannelHeader::Out,
         channel: Channel::Ch2,
     },
+    Query {
+        header: ChannelHeader::Sts,
+        channel: Channel::Ch1,
+    },
+    Query {
+        header: ChannelHeader::Sts,
+        channel: Channel::Ch2,
+    },
     Query {
         header: ChannelHeader::Vout,
         channel: Channel::Ch1,
@@ -142,9 +299,11 @@ impl Query {
         let q = match self.header {
             ChannelHeader::Vset => "VSET",
             ChannelHeader::Iset => "ISET",
+            ChannelHeader::Ovset => "OVSET",
             ChannelHeader::Vout => "VOUT",
             ChannelHeader::Iout => "IOUT",
             ChannelHeader::Out => "OUT",
+            ChannelHeader::Sts => "STS",
         };
 
         write!(s, "{}? {}", q, self.channel.to_str()).unwrap();
@@ -5,17 +5,22 @@ use stm32f4xx_hal::stm32::{RCC, TIM1};
 pub struct RotaryEncoder {
     timer: TIM1,
     count: i16,
+    steps: i16, // timer counts per detent
 }
 
 impl RotaryEncoder {
-    pub fn new(mut timer: TIM1) -> Self {
+    pub fn new(mut timer: TIM1, steps: i16) -> Self {
         setup_rotary_encoder_timer(&mut timer);
 
-        RotaryEncoder { timer, count: 0 }
+        RotaryEncoder {
+            timer,
+            count: 0,
+            steps: steps.max(1),
+        }
     }
 
     pub fn poll(&mut self) -> i16 {
-        let cnt = (self.timer.cnt.read().bits() as i16) >> 2;
+        let cnt = (self.timer.cnt.read().bits() as i16).div_euclid(self.steps);
         let (diff, _) = cnt.overflowing_sub(self.count);
         self.count = cnt;
         diff
@@ -0,0 +1,592 @@
+//! Project file scripts.
+//!
+//! A project file becomes a script after a `@script` directive,
+//! the rest of the file runs one statement at a time.
+//!
+//! ```text
+//! # comment
+//! set <name> <value> [<+|-|*|/> <value>]
+//! if <value> <<|>|<=|>=|==|!=> <value>
+//! else
+//! end
+//! repeat [count]
+//! end
+//! wait <ms>
+//! print <text>
+//! log <text>
+//! <instrument command>
+//! ```
+//!
+//! Values are numbers, variables (defined by their first `set`) or readings
+//! (`vout1`, `iout1`, `vout2`, `iout2`). `repeat` without a count loops forever.
+//! `$name` in text and instrument commands is replaced by the value.
+//! `print` shows the text on the script screen, `log` sends it to the USB host.
+//! Instrument commands are sent as they are, queries aren't allowed.
+
+use core::{fmt::Write, str::from_utf8};
+
+use heapless::{consts::*, ArrayLength, String, Vec};
+
+use crate::{
+    clock::Millis,
+    directive::*,
+    model::{PSChannel, VarSelected},
+    prelude::*,
+    protocol::Channel,
+    sequencer::RunState,
+};
+
+const MAX_VARS: usize = 16;
+const MAX_VAR_NAME: usize = 8;
+
+/// Don't hog the control loop with a script that never waits
+const MAX_STEPS_PER_TICK: usize = 32;
+
+const READINGS: [(&str, Channel, VarSelected); 4] = [
+    ("vout1", Channel::Ch1, VarSelected::V),
+    ("iout1", Channel::Ch1, VarSelected::I),
+    ("vout2", Channel::Ch2, VarSelected::V),
+    ("iout2", Channel::Ch2, VarSelected::I),
+];
+
+#[derive(Copy, Clone)]
+pub enum Value {
+    Num(f32),
+    Var(u8),
+    Reading(Channel, VarSelected),
+}
+
+#[derive(Copy, Clone)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Op {
+    fn parse(s: &str) -> Result<Self, AppError> {
+        match s {
+            "+" => Ok(Op::Add),
+            "-" => Ok(Op::Sub),
+            "*" => Ok(Op::Mul),
+            "/" => Ok(Op::Div),
+            _ => Err(AppError::ProjectFileError),
+        }
+    }
+
+    fn apply(&self, a: f32, b: f32) -> f32 {
+        match self {
+            Op::Add => a + b,
+            Op::Sub => a - b,
+            Op::Mul => a * b,
+            Op::Div => a / b,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum Cmp {
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Cmp {
+    fn parse(s: &str) -> Result<Self, AppError> {
+        match s {
+            "<" => Ok(Cmp::Lt),
+            ">" => Ok(Cmp::Gt),
+            "<=" => Ok(Cmp::Le),
+            ">=" => Ok(Cmp::Ge),
+            "==" => Ok(Cmp::Eq),
+            "!=" => Ok(Cmp::Ne),
+            _ => Err(AppError::ProjectFileError),
+        }
+    }
+
+    fn test(&self, a: f32, b: f32) -> bool {
+        match self {
+            Cmp::Lt => a < b,
+            Cmp::Gt => a > b,
+            Cmp::Le => a <= b,
+            Cmp::Ge => a >= b,
+            Cmp::Eq => a == b,
+            Cmp::Ne => a != b,
+        }
+    }
+}
+
+/// Where a script line goes
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Output {
+    Send, // instrument
+    Log,  // USB host
+}
+
+/// Slice of the script text pool
+#[derive(Copy, Clone)]
+struct Text {
+    start: u16,
+    len: u16,
+}
+
+#[derive(Copy, Clone)]
+enum Stmt {
+    Set {
+        var: u8,
+        a: Value,
+        op: Option<(Op, Value)>,
+    },
+    If {
+        a: Value,
+        cmp: Cmp,
+        b: Value,
+        skip: u16, // past else, or end
+    },
+    Else {
+        end: u16,
+    },
+    Repeat {
+        count: Option<Value>, // forever without one
+        end: u16,             // past the matching end
+    },
+    End {
+        repeat: Option<u16>,
+    },
+    Wait(Value),
+    Print(Text),
+    Out(Output, Text),
+}
+
+/// Parsed script file
+pub struct Script {
+    stmts: Vec<(u16, Stmt), U128>, // (file line, statement)
+    text: String<U1024>,
+    vars: Vec<String<U8>, U16>,
+    open: Vec<u16, U8>, // if/else/repeat without an end yet (nested 8 deep), while parsing
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script {
+            stmts: Vec::new(),
+            text: String::new(),
+            vars: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+
+    /// Add a statement from a file line, blank and '#' comment lines are skipped
+    pub fn parse_line(&mut self, line_no: u16, line: &[u8]) -> Result<(), AppError> {
+        let line = from_utf8(line)
+            .map_err(|_| AppError::ProjectFileError)?
+            .trim();
+        let mut args = line.split_whitespace();
+        let idx = self.stmts.len() as u16;
+
+        let stmt = match args.next() {
+            None => return Ok(()),
+            Some(a) if a.starts_with('#') => return Ok(()),
+            Some(a) if a.starts_with('@') => return Err(AppError::ProjectFileError),
+            Some(kw @ "print") => Stmt::Print(self.text(line[kw.len()..].trim())?),
+            Some(kw @ "log") => Stmt::Out(Output::Log, self.text(line[kw.len()..].trim())?),
+            Some("set") => {
+                let name = next_arg_str(&mut args)?;
+                let a = self.value(next_arg_str(&mut args)?)?;
+                let op = match args.next() {
+                    Some(o) => Some((Op::parse(o)?, self.value(next_arg_str(&mut args)?)?)),
+                    None => None,
+                };
+                Stmt::Set {
+                    var: self.define(name)?,
+                    a,
+                    op,
+                }
+            }
+            Some("if") => {
+                let a = self.value(next_arg_str(&mut args)?)?;
+                let cmp = Cmp::parse(next_arg_str(&mut args)?)?;
+                let b = self.value(next_arg_str(&mut args)?)?;
+                self.open_block(idx)?;
+                Stmt::If { a, cmp, b, skip: 0 }
+            }
+            Some("else") => {
+                let top = *self.open.last().ok_or(AppError::ProjectFileError)?;
+                match &mut self.stmts[top as usize].1 {
+                    Stmt::If { skip, .. } => *skip = idx + 1,
+                    _ => return Err(AppError::ProjectFileError),
+                }
+                self.open.pop();
+                self.open_block(idx)?;
+                Stmt::Else { end: 0 }
+            }
+            Some("end") => {
+                let start = self.open.pop().ok_or(AppError::ProjectFileError)?;
+                let mut repeat = None;
+                match &mut self.stmts[start as usize].1 {
+                    Stmt::If { skip, .. } => *skip = idx,
+                    Stmt::Else { end } => *end = idx,
+                    Stmt::Repeat { end, .. } => {
+                        *end = idx + 1;
+                        repeat = Some(start);
+                    }
+                    _ => return Err(AppError::Duh),
+                }
+                Stmt::End { repeat }
+            }
+            Some("repeat") => {
+                let count = match args.next() {
+                    Some(c) => match self.value(c)? {
+                        Value::Num(n) if !(n > 0.0 && n.is_finite()) => {
+                            return Err(AppError::ProjectFileError)
+                        }
+                        v => Some(v),
+                    },
+                    None => None,
+                };
+                self.open_block(idx)?;
+                Stmt::Repeat { count, end: 0 }
+            }
+            Some("wait") => Stmt::Wait(self.value(next_arg_str(&mut args)?)?),
+            Some(_) => {
+                // the response would end up parsed as a reading
+                if line.contains('?') {
+                    return Err(AppError::ProjectFileError);
+                }
+                Stmt::Out(Output::Send, self.text(line)?)
+            }
+        };
+
+        // text statements took the rest of the line
+        match stmt {
+            Stmt::Print(_) | Stmt::Out(..) => (),
+            _ => {
+                if args.next().is_some() {
+                    return Err(AppError::ProjectFileError);
+                }
+            }
+        }
+
+        self.stmts
+            .push((line_no, stmt))
+            .map_err(|_| AppError::ProjectFileError)
+    }
+
+    /// Done parsing, check the structure
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.open.is_empty() && !self.stmts.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::ProjectFileError)
+        }
+    }
+
+    #[inline]
+    fn open_block(&mut self, idx: u16) -> Result<(), AppError> {
+        self.open.push(idx).map_err(|_| AppError::ProjectFileError)
+    }
+
+    /// Variable (by its first `set`) or a reading
+    fn named(&self, name: &str) -> Option<Value> {
+        READINGS
+            .iter()
+            .find(|(n, _, _)| *n == name)
+            .map(|(_, ch, t)| Value::Reading(*ch, *t))
+            .or_else(|| {
+                self.vars
+                    .iter()
+                    .position(|v| v.as_str() == name)
+                    .map(|i| Value::Var(i as u8))
+            })
+    }
+
+    fn value(&self, s: &str) -> Result<Value, AppError> {
+        match s.parse::<f32>() {
+            Ok(n) => Ok(Value::Num(n)),
+            Err(_) => self.named(s).ok_or(AppError::ProjectFileError),
+        }
+    }
+
+    /// Slot of a variable, a new one the first time it's set
+    fn define(&mut self, name: &str) -> Result<u8, AppError> {
+        match self.named(name) {
+            Some(Value::Var(i)) => return Ok(i),
+            Some(_) => return Err(AppError::ProjectFileError), // a reading
+            _ => (),
+        }
+
+        let valid = name.len() <= MAX_VAR_NAME
+            && name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name == var_name(name);
+        if !valid || self.vars.len() >= MAX_VARS {
+            return Err(AppError::ProjectFileError);
+        }
+
+        self.vars
+            .push(String::from(name))
+            .map_err(|_| AppError::ProjectFileError)?;
+        Ok((self.vars.len() - 1) as u8)
+    }
+
+    /// Add to the text pool, `$name`s have to be known by now
+    fn text(&mut self, s: &str) -> Result<Text, AppError> {
+        for p in s.split('$').skip(1) {
+            self.named(var_name(p)).ok_or(AppError::ProjectFileError)?;
+        }
+
+        let start = self.text.len() as u16;
+        self.text
+            .push_str(s)
+            .map_err(|_| AppError::ProjectFileError)?;
+        Ok(Text {
+            start,
+            len: s.len() as u16,
+        })
+    }
+}
+
+/// Leading identifier characters
+fn var_name(s: &str) -> &str {
+    let n = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    &s[..n]
+}
+
+/// Runs a script, a few statements at a time
+pub struct ScriptRun {
+    script: Script,
+    pc: usize,                          // next statement
+    loops: Vec<(u16, Option<u32>), U8>, // (repeat statement, iterations left)
+    vals: [f32; MAX_VARS],
+    line: u16,         // of the last statement executed
+    remaining_ms: i64, // of the current wait, overshoot carries over
+    last_tick: Option<Millis>,
+    pub state: RunState,
+    pub pending: Option<(Output, String<U64>)>, // to be sent by the controller
+    pub printed: String<U32>,
+}
+
+impl ScriptRun {
+    pub fn new(script: Script) -> Self {
+        ScriptRun {
+            script,
+            pc: 0,
+            loops: Vec::new(),
+            vals: [0.0; MAX_VARS],
+            line: 0,
+            remaining_ms: 0,
+            last_tick: None,
+            state: RunState::Running,
+            pending: None,
+            printed: String::new(),
+        }
+    }
+
+    /// File line of the statement being executed
+    #[inline]
+    pub fn line(&self) -> u16 {
+        self.line
+    }
+
+    /// Time left in the current wait
+    #[inline]
+    pub fn remaining_ms(&self) -> u32 {
+        self.remaining_ms.max(0) as u32
+    }
+
+    /// Variable names and values, in the order they were defined
+    pub fn vars<'s>(&'s self) -> impl Iterator<Item = (&'s str, f32)> + 's {
+        self.script
+            .vars
+            .iter()
+            .zip(self.vals.iter())
+            .map(|(n, v)| (n.as_str(), *v))
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.state = match self.state {
+            RunState::Running => RunState::Paused,
+            RunState::Paused => RunState::Running,
+            RunState::Done => RunState::Done,
+        }
+    }
+
+    #[inline]
+    pub fn stop(&mut self) {
+        self.state = RunState::Done;
+        self.pending = None;
+    }
+
+    /// Periodic update, runs statements until a wait, an output line or a missing reading
+    pub fn tick(&mut self, now: Millis, ch1: &PSChannel, ch2: &PSChannel) -> Result<(), AppError> {
+        let dt = now.saturating_sub(*self.last_tick.get_or_insert(now));
+        self.last_tick = Some(now);
+
+        if self.state != RunState::Running {
+            return Ok(());
+        }
+
+        if self.remaining_ms > 0 {
+            self.remaining_ms -= dt as i64;
+        }
+
+        let mut n = MAX_STEPS_PER_TICK;
+        while self.state == RunState::Running
+            && self.remaining_ms <= 0
+            && self.pending.is_none()
+            && n > 0
+        {
+            if !self.step(ch1, ch2)? {
+                break; // no reading yet
+            }
+            n -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// Execute a statement, false if it has to wait for a reading
+    fn step(&mut self, ch1: &PSChannel, ch2: &PSChannel) -> Result<bool, AppError> {
+        let (line, stmt) = match self.script.stmts.get(self.pc) {
+            Some(s) => *s,
+            None => {
+                self.state = RunState::Done;
+                return Ok(false);
+            }
+        };
+        self.line = line;
+        let mut next = self.pc + 1;
+
+        match stmt {
+            Stmt::Set { var, a, op } => {
+                let a = match self.eval(a, ch1, ch2) {
+                    Some(a) => a,
+                    None => return Ok(false),
+                };
+                self.vals[var as usize] = match op {
+                    Some((op, b)) => match self.eval(b, ch1, ch2) {
+                        Some(b) => op.apply(a, b),
+                        None => return Ok(false),
+                    },
+                    None => a,
+                };
+            }
+            Stmt::If { a, cmp, b, skip } => {
+                match (self.eval(a, ch1, ch2), self.eval(b, ch1, ch2)) {
+                    (Some(a), Some(b)) => {
+                        if !cmp.test(a, b) {
+                            next = skip as usize;
+                        }
+                    }
+                    _ => return Ok(false),
+                }
+            }
+            Stmt::Else { end } => next = end as usize,
+            Stmt::Repeat { count, end } => {
+                let left = match count {
+                    Some(c) => match self.eval(c, ch1, ch2) {
+                        Some(c) if !(c >= 1.0) => None, // NaN too
+                        Some(c) => Some(Some(c as u32 - 1)),
+                        None => return Ok(false),
+                    },
+                    None => Some(None),
+                };
+                match left {
+                    Some(left) => self
+                        .loops
+                        .push((self.pc as u16, left))
+                        .map_err(|_| AppError::Duh)?,
+                    None => next = end as usize, // zero times
+                }
+            }
+            Stmt::End {
+                repeat: Some(start),
+            } => match self.loops.last_mut() {
+                Some((s, left)) if *s == start => match left {
+                    Some(0) => {
+                        self.loops.pop();
+                    }
+                    Some(n) => {
+                        *n -= 1;
+                        next = start as usize + 1;
+                    }
+                    None => next = start as usize + 1,
+                },
+                _ => return Err(AppError::Duh),
+            },
+            Stmt::End { repeat: None } => (),
+            Stmt::Wait(ms) => match self.eval(ms, ch1, ch2) {
+                Some(ms) => self.remaining_ms += ms.max(0.0) as i64,
+                None => return Ok(false),
+            },
+            Stmt::Print(t) => {
+                let mut s = String::new();
+                if !self.expand(t, ch1, ch2, &mut s)? {
+                    return Ok(false);
+                }
+                self.printed = s;
+            }
+            Stmt::Out(o, t) => {
+                let mut s = String::new();
+                if !self.expand(t, ch1, ch2, &mut s)? {
+                    return Ok(false);
+                }
+                self.pending = Some((o, s));
+            }
+        }
+
+        self.pc = next;
+        Ok(true)
+    }
+
+    fn eval(&self, v: Value, ch1: &PSChannel, ch2: &PSChannel) -> Option<f32> {
+        match v {
+            Value::Num(n) => Some(n),
+            Value::Var(i) => Some(self.vals[i as usize]),
+            Value::Reading(ch, t) => {
+                let psch = match ch {
+                    Channel::Ch1 => ch1,
+                    Channel::Ch2 => ch2,
+                };
+                match t {
+                    VarSelected::V => psch.vout,
+                    VarSelected::I => psch.iout,
+                }
+            }
+        }
+    }
+
+    /// Text with `$name`s replaced by values, false if a reading isn't there yet
+    fn expand<S>(
+        &self,
+        t: Text,
+        ch1: &PSChannel,
+        ch2: &PSChannel,
+        buf: &mut String<S>,
+    ) -> Result<bool, AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        let src = &self.script.text[t.start as usize..(t.start + t.len) as usize];
+        let mut parts = src.split('$');
+        buf.push_str(parts.next().unwrap_or(""))
+            .map_err(|_| AppError::ProjectFileError)?;
+
+        for p in parts {
+            let name = var_name(p);
+            let v = self.script.named(name).and_then(|v| self.eval(v, ch1, ch2));
+            match v {
+                Some(v) => write!(buf, "{:.3}{}", v, &p[name.len()..])
+                    .map_err(|_| AppError::ProjectFileError)?,
+                None => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+}
@@ -1,22 +1,59 @@
 use cortex_m_semihosting::*;
 
-use core::ops::FnMut;
+use core::{fmt::Write, ops::FnMut};
 
-use embedded_sdmmc::{filesystem::Mode, SdMmcError, Volume, VolumeIdx};
+use embedded_sdmmc::{filesystem::Mode, Directory, SdMmcError, Volume, VolumeIdx};
 
 use heapless::{consts::*, String, Vec};
 
+use crate::line::fill_until_eol;
 use crate::prelude::*;
 use crate::*;
 
+/// Appended to directory names in listings
+pub const DIR_MARKER: char = '/';
+
+/// A card inserted after startup gets fewer init attempts, there may be none
+const CARD_INIT_TRIES: u32 = 10;
+
+/// ("DIR/SUB", "FILE") of "DIR/SUB/FILE", the directory is empty in the root
+pub fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Directory entry details
+#[derive(Copy, Clone)]
+pub struct FileInfo {
+    pub size: u32,
+    pub year: u16, // modified
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+}
+
 pub struct SDCard {
     controller: SDCardController,
+    present: bool, // initialised, until it stops answering
 }
 
 impl SDCard {
-    pub fn new(mut controller: SDCardController) -> Result<SDCard, AppError> {
+    /// No card is fine, it's initialised once it's needed
+    pub fn new(controller: SDCardController) -> SDCard {
+        let mut sdc = SDCard {
+            controller,
+            present: false,
+        };
+        sdc.init(100).ok();
+        sdc
+    }
+
+    fn init(&mut self, mut num_tries: u32) -> Result<(), AppError> {
+        let controller = &mut self.controller;
         let mut sdres = controller.device().init();
-        let mut num_tries = 100;
         while num_tries > 0 && sdres.is_err() {
             num_tries -= 1;
             ifcfg!("sdc_debug", hprintln!("{:?}!", sdres));
@@ -29,6 +66,7 @@ impl SDCard {
             ifcfg!("sdc_info", hprintln!("SD init OK!"));
         }
 
+        self.present = sdres.is_ok();
         sdres?;
 
         ifcfg!(
@@ -36,93 +74,354 @@ impl SDCard {
             hprintln!("Card size {}", controller.device().card_size_bytes()?)
         );
 
-        Ok(SDCard { controller })
+        Ok(())
     }
 
+    /// As of the last access (or check)
+    #[inline]
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// The card is still there (reads a register), or one was inserted
+    /// (a single init attempt)
+    pub fn check_present(&mut self) -> bool {
+        if !self.present {
+            self.init(0).ok();
+        } else if self.controller.device().card_size_bytes().is_err() {
+            ifcfg!("sdc_info", hprintln!("SD card gone"));
+            self.present = false;
+        }
+        self.present
+    }
+
+    /// Every access starts here, (re)initialises the card if it has to
     #[inline]
     fn get_volume(&mut self) -> Result<Volume, AppError> {
+        if !self.present {
+            self.init(CARD_INIT_TRIES)?;
+        }
+
         let volres = self.controller.get_volume(VolumeIdx(0));
         if volres.is_err() {
             ifcfg!("sdc_info", hprintln!("Volume 0 {:?}", volres));
         }
-        Ok(volres?)
+        let vol = volres.map_err(AppError::from);
+        if let Err(AppError::NoCard) = vol {
+            self.present = false;
+        }
+        vol
     }
 
-    #[inline]
-    pub fn send_boot_file<F>(&mut self, func: F) -> Result<(), AppError>
+    /// Directory of a '/' separated path from the root, empty path is the root itself
+    fn open_dir_path(&mut self, vol: &Volume, path: &str) -> Result<Directory, AppError> {
+        let mut dir = self.controller.open_root_dir(vol)?;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            let res = self.controller.open_dir(vol, &dir, name);
+            self.controller.close_dir(vol, dir);
+            dir = res?;
+        }
+        Ok(dir)
+    }
+
+    /// Send a file (path from the root) in chunks
+    pub fn send_file<F>(&mut self, path: &str, mut func: F) -> Result<(), AppError>
     where
         F: FnMut(&[u8]) -> Result<(), AppError>,
     {
-        self.send_file("BOOT", func)
+        self.send_file_while(path, |buf| func(buf).map(|_| true))
     }
 
-    pub fn send_file<F>(&mut self, fname: &str, mut func: F) -> Result<(), AppError>
+    /// Send a file in chunks until `func` returns false
+    pub fn send_file_while<F>(&mut self, path: &str, mut func: F) -> Result<(), AppError>
     where
-        F: FnMut(&[u8]) -> Result<(), AppError>,
+        F: FnMut(&[u8]) -> Result<bool, AppError>,
     {
         let mut vol = self.get_volume()?;
-        let dir = self.controller.open_root_dir(&vol)?;
+        let (dir_path, fname) = split_path(path);
+        let dir = self.open_dir_path(&vol, dir_path)?;
 
-        ifcfg!("sdc_info", hprintln!("send_file {}", fname));
+        ifcfg!("sdc_info", hprintln!("send_file {}", path));
 
-        let mut f = self
+        let mut f = match self
             .controller
-            .open_file_in_dir(&mut vol, &dir, fname, Mode::ReadOnly)?;
+            .open_file_in_dir(&mut vol, &dir, fname, Mode::ReadOnly)
+        {
+            Ok(f) => f,
+            Err(e) => {
+                self.controller.close_dir(&vol, dir);
+                return Err(e.into());
+            }
+        };
 
         let mut buf: [u8; 128] = [0; 128];
-        let mut nbytes = self.controller.read(&mut vol, &mut f, &mut buf)?;
-        while nbytes > 0 {
-            ifcfg!("sdc_info", hprintln!("sending: {}", nbytes));
-            func(&buf[0..nbytes])?;
-            nbytes = self.controller.read(&vol, &mut f, &mut buf)?;
+        let mut res = Ok(());
+        loop {
+            match self.controller.read(&vol, &mut f, &mut buf) {
+                Ok(0) => break,
+                Ok(nbytes) => {
+                    ifcfg!("sdc_info", hprintln!("sending: {}", nbytes));
+                    match func(&buf[0..nbytes]) {
+                        Ok(true) => (),
+                        Ok(false) => break,
+                        Err(e) => {
+                            res = Err(e);
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    res = Err(e.into());
+                    break;
+                }
+            }
         }
 
+        // close them anyway, there are only a few file/directory handles
         self.controller.close_file(&vol, f)?;
         self.controller.close_dir(&vol, dir);
+        res
+    }
+
+    /// Size and modification time of a file (path from the root)
+    pub fn file_info(&mut self, path: &str) -> Result<FileInfo, AppError> {
+        let vol = self.get_volume()?;
+        let (dir_path, fname) = split_path(path);
+        let dir = self.open_dir_path(&vol, dir_path)?;
+
+        let res = self.controller.find_directory_entry(&vol, &dir, fname);
+        self.controller.close_dir(&vol, dir);
+
+        let e = res?;
+        Ok(FileInfo {
+            size: e.size,
+            year: 1970 + e.mtime.year_since_1970 as u16,
+            month: e.mtime.zero_indexed_month + 1,
+            day: e.mtime.zero_indexed_day + 1,
+            hour: e.mtime.hours,
+            minute: e.mtime.minutes,
+        })
+    }
+
+    /// Send a file one line at a time (including '\n')
+    pub fn read_lines<F>(&mut self, path: &str, mut func: F) -> Result<(), AppError>
+    where
+        F: FnMut(&[u8]) -> Result<(), AppError>,
+    {
+        self.read_lines_while(path, |line| func(line).map(|_| true))
+    }
+
+    /// Send a file one line at a time until `func` returns false
+    pub fn read_lines_while<F>(&mut self, path: &str, mut func: F) -> Result<(), AppError>
+    where
+        F: FnMut(&[u8]) -> Result<bool, AppError>,
+    {
+        let mut line: Vec<u8, U128> = Vec::new();
+        let mut more = true;
+
+        self.send_file_while(path, |buf| {
+            let mut data: Vec<u8, U128> = Vec::from_slice(buf).map_err(|_| AppError::Duh)?;
+            while more && !data.is_empty() {
+                if fill_until_eol(&mut line, &mut data) {
+                    more = func(&line)?;
+                    line.clear();
+                } else if line.len() == line.capacity() {
+                    return Err(AppError::ProjectFileError); // line is too long
+                }
+            }
+            Ok(more)
+        })?;
+
+        // last line without '\n'
+        if more && !line.is_empty() {
+            func(&line)?;
+        }
+
         Ok(())
     }
 
-    /// List files in the root directory
+    /// Create (or truncate) a file in the root directory,
+    /// `func` fills the buffer one chunk at a time until it returns false
+    pub fn write_file<F>(&mut self, fname: &str, mut func: F) -> Result<(), AppError>
+    where
+        F: FnMut(&mut String<U128>) -> Result<bool, AppError>,
+    {
+        let mut vol = self.get_volume()?;
+        let dir = self.controller.open_root_dir(&vol)?;
+
+        ifcfg!("sdc_info", hprintln!("write_file {}", fname));
+
+        let mut f = self.controller.open_file_in_dir(
+            &mut vol,
+            &dir,
+            fname,
+            Mode::ReadWriteCreateOrTruncate,
+        )?;
+
+        let mut buf: String<U128> = String::new();
+        let mut res = Ok(());
+        loop {
+            buf.clear();
+            match func(&mut buf) {
+                Ok(more) => {
+                    if !buf.is_empty() {
+                        if let Err(e) = self.controller.write(&mut vol, &mut f, buf.as_bytes()) {
+                            res = Err(e.into());
+                            break;
+                        }
+                    }
+                    if !more {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // close it anyway, so that whatever was written makes it to the card
+        self.controller.close_file(&vol, f)?;
+        self.controller.close_dir(&vol, dir);
+        res
+    }
+
+    /// Append to a file in the root directory, it's created if it isn't there
+    pub fn append_file(&mut self, fname: &str, data: &[u8]) -> Result<(), AppError> {
+        let mut vol = self.get_volume()?;
+        let dir = self.controller.open_root_dir(&vol)?;
+
+        ifcfg!(
+            "sdc_info",
+            hprintln!("append_file {} {}", fname, data.len())
+        );
+
+        let mut f = match self.controller.open_file_in_dir(
+            &mut vol,
+            &dir,
+            fname,
+            Mode::ReadWriteCreateOrAppend,
+        ) {
+            Ok(f) => f,
+            Err(e) => {
+                self.controller.close_dir(&vol, dir);
+                return Err(e.into());
+            }
+        };
+
+        let res = self.controller.write(&mut vol, &mut f, data);
+
+        // close it anyway, so that whatever was written makes it to the card
+        self.controller.close_file(&vol, f)?;
+        self.controller.close_dir(&vol, dir);
+        res.map(|_| ()).map_err(|e| e.into())
+    }
+
+    /// First unused "<prefix><nnn>.<ext>" name in the root directory
+    pub fn next_file_name(&mut self, prefix: &str, ext: &str) -> Result<String<U16>, AppError> {
+        let vol = self.get_volume()?;
+        let dir = self.controller.open_root_dir(&vol)?;
+
+        let mut next = 0u32;
+        self.controller.iterate_dir(&vol, &dir, |e| {
+            let bn = e.name.base_name();
+            if bn.starts_with(prefix.as_bytes()) && e.name.extension() == ext.as_bytes() {
+                let n = core::str::from_utf8(&bn[prefix.len()..])
+                    .ok()
+                    .and_then(|ns| ns.parse::<u32>().ok());
+                match n {
+                    Some(n) => next = next.max(n + 1),
+                    None => (),
+                }
+            }
+        })?;
+
+        self.controller.close_dir(&vol, dir);
+
+        let mut fname = String::new();
+        write!(fname, "{}{:03}.{}", prefix, next, ext)?;
+        Ok(fname)
+    }
+
+    /// List files (short names, "NAME.EXT") and directories (with a `DIR_MARKER`) in a directory,
+    /// directories first. Only files with one of `exts` (upper case) are listed, unless it's empty.
+    /// They're added after any entries already in `fnames`, which keep their place.
+    /// The FAT library doesn't read long file names.
     pub fn list_projects_files(
         &mut self,
+        dir_path: &str,
+        exts: &[String<U3>],
         fnames: &mut Vec<String<U32>, U64>,
     ) -> Result<(), AppError> {
-        ifcfg!("sdc_info", hprintln!("list_proj_files"));
+        ifcfg!("sdc_info", hprintln!("list_proj_files {}", dir_path));
 
         let vol = self.get_volume()?;
-        let dir = self.controller.open_root_dir(&vol)?;
+        let dir = self.open_dir_path(&vol, dir_path)?;
 
+        let first = fnames.len(); // entries already there stay on top
         let mut err = None::<AppError>;
         self.controller.iterate_dir(&vol, &dir, |e| {
             ifcfg!("sdc_debug", hprintln!("entry: {:?}", e.name));
 
-            if !(e.attributes.is_volume() || e.attributes.is_directory()) {
+            let bn = e.name.base_name();
+            let ext = e.name.extension();
+            let is_dir = e.attributes.is_directory();
+            let listed = is_dir || exts.is_empty() || exts.iter().any(|x| x.as_bytes() == ext);
+            if !(e.attributes.is_volume() || bn.starts_with(b".")) && listed {
                 ifcfg!("sdc_debug", hprintln!("adding: {:?}", e.name));
-                let bn = e.name.base_name();
-                let res = Vec::from_slice(bn)
+                let mut fv: Vec<u8, U32> = Vec::new();
+                let res = fv
+                    .extend_from_slice(bn)
+                    .and_then(|_| {
+                        if ext.is_empty() {
+                            Ok(())
+                        } else {
+                            fv.push(b'.').map_err(|_| ())?;
+                            fv.extend_from_slice(ext)
+                        }
+                    })
                     .map_err(|_| AppError::ProjectFileError)
-                    .and_then(|fv| String::from_utf8(fv).map_err(|_| AppError::ProjectFileError))
-                    .and_then(|fs| fnames.push(fs).map_err(|_| AppError::ProjectFileError));
+                    .and_then(|_| String::from_utf8(fv).map_err(|_| AppError::ProjectFileError))
+                    .and_then(|mut fs| {
+                        if is_dir {
+                            fs.push(DIR_MARKER)
+                                .map_err(|_| AppError::ProjectFileError)?;
+                        }
+                        fnames.push(fs).map_err(|_| AppError::ProjectFileError)
+                    });
                 err = err.or(res.err());
             }
         })?;
 
         self.controller.close_dir(&vol, dir);
 
-        core::slice::heapsort(fnames, |a, b| a.as_str() > b.as_str());
+        core::slice::heapsort(&mut fnames[first..], |a, b| {
+            let (a_dir, b_dir) = (a.ends_with(DIR_MARKER), b.ends_with(DIR_MARKER));
+            if a_dir != b_dir {
+                a_dir
+            } else {
+                a.as_str() > b.as_str()
+            }
+        });
 
         err.map(|e| Err(e)).unwrap_or(Ok(()))
     }
 }
 
+/// The card didn't answer (or there's none)
 impl From<SdMmcError> for AppError {
     fn from(_: SdMmcError) -> Self {
-        AppError::SDError
+        AppError::NoCard
     }
 }
 
+/// Card errors apart from file system ones
 impl From<embedded_sdmmc::Error<SdMmcError>> for AppError {
-    fn from(_: embedded_sdmmc::Error<SdMmcError>) -> Self {
-        AppError::SDError
+    fn from(e: embedded_sdmmc::Error<SdMmcError>) -> Self {
+        match e {
+            embedded_sdmmc::Error::DeviceError(_) => AppError::NoCard,
+            _ => AppError::SDError,
+        }
     }
 }
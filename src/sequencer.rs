@@ -0,0 +1,314 @@
+//! Timed setpoint sequencer.
+//!
+//! A project file becomes a sequence after a `@sequence` directive,
+//! the rest of the file is a list of steps, each holds its setpoints for a while.
+//!
+//! ```text
+//! @sequence [cycles]
+//! @loop <n>
+//! <hold seconds> <ch> <V> <I> <on|off> [<ch> <V> <I> <on|off>]
+//! @end
+//! ```
+//!
+//! The whole list runs `cycles` times (0 is forever, default 1),
+//! steps between `@loop` and `@end` repeat `n` times (at least once, with at least
+//! one step in between).
+//! Channels not mentioned in a step are left as they are.
+
+use core::str::from_utf8;
+
+use heapless::{consts::*, Vec};
+
+use crate::{clock::Millis, directive::*, prelude::*, protocol::*};
+
+/// Shortest hold, steps can't be timed better than the control loop anyway
+pub const MIN_HOLD_MS: u32 = 100;
+
+const MAX_LOOP_DEPTH: usize = 4;
+
+/// Setpoints of a single channel
+#[derive(Copy, Clone)]
+pub struct Setpoint {
+    pub v: f32,
+    pub i: f32,
+    pub on: bool,
+}
+
+#[derive(Copy, Clone)]
+pub enum Step {
+    Set {
+        hold_ms: u32,
+        ch1: Option<Setpoint>,
+        ch2: Option<Setpoint>,
+    },
+    Loop {
+        count: u16,
+    },
+    EndLoop,
+}
+
+/// Parsed sequence file
+pub struct Sequence {
+    steps: Vec<Step, U64>,
+    cycles: u16,
+    depth: usize, // open loops while parsing
+}
+
+impl Sequence {
+    pub fn new(cycles: u16) -> Self {
+        Sequence {
+            steps: Vec::new(),
+            cycles,
+            depth: 0,
+        }
+    }
+
+    /// Add a step from a file line, blank and '#' comment lines are skipped
+    pub fn parse_line(&mut self, line: &[u8]) -> Result<(), AppError> {
+        let line = from_utf8(line).map_err(|_| AppError::ProjectFileError)?;
+        let mut args = line.split_whitespace();
+
+        let step = match args.next() {
+            None => return Ok(()),
+            Some(a) if a.starts_with('#') => return Ok(()),
+            Some("@loop") => {
+                self.depth += 1;
+                if self.depth > MAX_LOOP_DEPTH {
+                    return Err(AppError::ProjectFileError);
+                }
+                let count = parse_arg(next_arg_str(&mut args)?)?;
+                if count == 0 {
+                    return Err(AppError::ProjectFileError);
+                }
+                Step::Loop { count }
+            }
+            Some("@end") => {
+                // an empty loop would run without ever holding a step
+                let empty = match self.steps.last() {
+                    Some(Step::Loop { .. }) => true,
+                    _ => false,
+                };
+                if self.depth == 0 || empty {
+                    return Err(AppError::ProjectFileError);
+                }
+                self.depth -= 1;
+                Step::EndLoop
+            }
+            Some(hold_s) => {
+                let hold_ms = (parse_arg::<f32>(hold_s)? * 1000.0) as u32;
+                if hold_ms < MIN_HOLD_MS {
+                    return Err(AppError::ProjectFileError);
+                }
+
+                let mut ch1 = None;
+                let mut ch2 = None;
+                while let Some(chs) = args.next() {
+                    let sp = Setpoint {
+                        v: parse_arg(next_arg_str(&mut args)?)?,
+                        i: parse_arg(next_arg_str(&mut args)?)?,
+                        on: match next_arg_str(&mut args)? {
+                            "on" => true,
+                            "off" => false,
+                            _ => return Err(AppError::ProjectFileError),
+                        },
+                    };
+                    match Channel::parse(chs)? {
+                        Channel::Ch1 => ch1 = Some(sp),
+                        Channel::Ch2 => ch2 = Some(sp),
+                    }
+                }
+
+                Step::Set { hold_ms, ch1, ch2 }
+            }
+        };
+
+        self.steps
+            .push(step)
+            .map_err(|_| AppError::ProjectFileError)
+    }
+
+    /// Done parsing, check the structure
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.depth == 0 && self.num_set_steps() > 0 {
+            Ok(())
+        } else {
+            Err(AppError::ProjectFileError)
+        }
+    }
+
+    /// Number of steps that change setpoints
+    pub fn num_set_steps(&self) -> usize {
+        count_set_steps(&self.steps)
+    }
+
+    #[inline]
+    pub fn cycles(&self) -> u16 {
+        self.cycles
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum RunState {
+    Running,
+    Paused,
+    Done,
+}
+
+/// Runs a sequence, one step at a time
+pub struct Sequencer {
+    seq: Sequence,
+    pc: usize,                    // next step to execute
+    loops: Vec<(usize, u16), U4>, // (loop body start, iterations left)
+    cycle: u16,                   // 1 based
+    remaining_ms: i64,            // of the current step, overshoot carries over
+    last_tick: Option<Millis>,
+    pub state: RunState,
+}
+
+impl Sequencer {
+    pub fn new(seq: Sequence) -> Self {
+        Sequencer {
+            seq,
+            pc: 0,
+            loops: Vec::new(),
+            cycle: 1,
+            remaining_ms: 0,
+            last_tick: None,
+            state: RunState::Running,
+        }
+    }
+
+    /// Position of the current step in the list, 1 based
+    #[inline]
+    pub fn step(&self) -> usize {
+        count_set_steps(&self.seq.steps[..self.pc])
+    }
+
+    #[inline]
+    pub fn num_steps(&self) -> usize {
+        self.seq.num_set_steps()
+    }
+
+    #[inline]
+    pub fn cycle(&self) -> u16 {
+        self.cycle
+    }
+
+    #[inline]
+    pub fn cycles(&self) -> u16 {
+        self.seq.cycles()
+    }
+
+    /// Time left in the current step
+    #[inline]
+    pub fn remaining_ms(&self) -> u32 {
+        self.remaining_ms.max(0) as u32
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.state = match self.state {
+            RunState::Running => RunState::Paused,
+            RunState::Paused => RunState::Running,
+            RunState::Done => RunState::Done,
+        }
+    }
+
+    /// Periodic update, sends setpoints of the next step once the current one is over
+    pub fn tick(&mut self, now: Millis, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        let dt = now.saturating_sub(*self.last_tick.get_or_insert(now));
+        self.last_tick = Some(now);
+
+        if self.state != RunState::Running {
+            return Ok(());
+        }
+
+        self.remaining_ms -= dt as i64;
+
+        // catch up after a stall, but don't spin through the whole list more than once
+        let mut n = self.seq.steps.len();
+        while self.remaining_ms <= 0 && self.state == RunState::Running && n > 0 {
+            self.next_step(cmds)?;
+            n -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// Execute steps up to the next set step (or the end of the sequence)
+    fn next_step(&mut self, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        // a set step is reached within two passes over the list, unless the file is broken
+        for _ in 0..2 * self.seq.steps.len() + 1 {
+            if self.pc >= self.seq.steps.len() {
+                if self.seq.cycles != 0 && self.cycle >= self.seq.cycles {
+                    self.state = RunState::Done;
+                    return Ok(());
+                }
+                self.pc = 0;
+                self.loops.clear();
+                self.cycle = self.cycle.saturating_add(1);
+            }
+
+            let s = self.seq.steps[self.pc];
+            self.pc += 1;
+
+            match s {
+                Step::Set { hold_ms, ch1, ch2 } => {
+                    ch1.map_or(Ok(()), |sp| setpoint_cmds(Channel::Ch1, &sp, cmds))?;
+                    ch2.map_or(Ok(()), |sp| setpoint_cmds(Channel::Ch2, &sp, cmds))?;
+                    self.remaining_ms += hold_ms as i64;
+                    return Ok(());
+                }
+                Step::Loop { count } => {
+                    if count == 0 {
+                        self.skip_loop();
+                    } else {
+                        self.loops
+                            .push((self.pc, count - 1))
+                            .map_err(|_| AppError::Duh)?;
+                    }
+                }
+                Step::EndLoop => match self.loops.last_mut() {
+                    Some((start, left)) if *left > 0 => {
+                        *left -= 1;
+                        self.pc = *start;
+                    }
+                    _ => {
+                        self.loops.pop();
+                    }
+                },
+            }
+        }
+
+        self.state = RunState::Done;
+        Ok(())
+    }
+
+    /// Jump past the matching @end
+    fn skip_loop(&mut self) {
+        let mut depth = 1;
+        while self.pc < self.seq.steps.len() && depth > 0 {
+            match self.seq.steps[self.pc] {
+                Step::Loop { .. } => depth += 1,
+                Step::EndLoop => depth -= 1,
+                _ => (),
+            }
+            self.pc += 1;
+        }
+    }
+}
+
+fn count_set_steps(steps: &[Step]) -> usize {
+    steps
+        .iter()
+        .filter(|s| match s {
+            Step::Set { .. } => true,
+            _ => false,
+        })
+        .count()
+}
+
+fn setpoint_cmds(ch: Channel, sp: &Setpoint, cmds: &mut CommandQueue) -> Result<(), AppError> {
+    cmds.push(Command::Vset { ch, val: sp.v })?;
+    cmds.push(Command::Iset { ch, val: sp.i })?;
+    cmds.push(Command::Out { ch, on: sp.on })
+}
@@ -0,0 +1,295 @@
+//! Last session, saved to `SESSION` (SD card root) a while after it changes
+//! and offered at startup.
+//!
+//! ```text
+//! vset 1 5.000
+//! iset 1 0.100
+//! vset 2 12.000
+//! iset 2 1.000
+//! select both v
+//! screen info
+//! ```
+//!
+//! Restoring it sends the setpoints with both outputs off, then opens the screen.
+
+use core::fmt::Write;
+use core::str::from_utf8;
+
+use heapless::{consts::*, String};
+
+use stm32f4xx_hal::time::MilliSeconds;
+
+use crate::{
+    clock::Millis,
+    directive::{next_arg_str, parse_arg},
+    model::{ChSelected, MenuItem, PSChannel, VarSelected, UI},
+    prelude::*,
+    protocol::Channel,
+    sdcard::SDCard,
+};
+
+pub const SESSION_FILE: &str = "SESSION";
+
+/// Saved once nothing has changed for this long
+const SAVE_DELAY_MS: u64 = 5000;
+
+/// Another try after a failed save (no card, most likely)
+const RETRY_MS: u64 = 30_000;
+
+/// Screens that are restored, by their name in the file
+const SCREENS: [(&str, MenuItem); 8] = [
+    ("stats", MenuItem::Stats),
+    ("graph1", MenuItem::Graph(Channel::Ch1)),
+    ("graph2", MenuItem::Graph(Channel::Ch2)),
+    ("waveform", MenuItem::Waveform),
+    ("charger", MenuItem::Charger),
+    ("sweep", MenuItem::Sweep),
+    ("cal", MenuItem::Calibration),
+    ("settings", MenuItem::Settings),
+];
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Session {
+    pub vset: [f32; 2],
+    pub iset: [f32; 2],
+    pub chsel: ChSelected,
+    pub vsel: VarSelected,
+    pub screen: Option<MenuItem>, // info screen if none
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            vset: [0.0; 2],
+            iset: [0.0; 2],
+            chsel: ChSelected::Both,
+            vsel: VarSelected::V,
+            screen: None,
+        }
+    }
+
+    /// Saved session, if there's one and all of it parses
+    pub fn load(sdc: &mut SDCard) -> Option<Self> {
+        let mut s = Session::new();
+        let mut setpoints = 0;
+        sdc.read_lines(SESSION_FILE, |line| {
+            if s.parse_line(line)? {
+                setpoints += 1;
+            }
+            Ok(())
+        })
+        .ok()?;
+
+        if setpoints == 4 {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    /// True if it's a setpoint
+    fn parse_line(&mut self, line: &[u8]) -> Result<bool, AppError> {
+        let line = from_utf8(line).map_err(|_| AppError::ProjectFileError)?;
+        let mut args = line.split_whitespace();
+        let key = match args.next() {
+            Some(k) => k,
+            None => return Ok(false),
+        };
+
+        let setpoint = match key {
+            "vset" | "iset" => {
+                let i = match Channel::parse(next_arg_str(&mut args)?)? {
+                    Channel::Ch1 => 0,
+                    Channel::Ch2 => 1,
+                };
+                let val: f32 = parse_arg(next_arg_str(&mut args)?)?;
+                if key == "vset" {
+                    self.vset[i] = val;
+                } else {
+                    self.iset[i] = val;
+                }
+                true
+            }
+            "select" => {
+                self.chsel = match next_arg_str(&mut args)? {
+                    "both" => ChSelected::Both,
+                    "1" => ChSelected::Ch1,
+                    "2" => ChSelected::Ch2,
+                    _ => return Err(AppError::ProjectFileError),
+                };
+                self.vsel = match next_arg_str(&mut args)? {
+                    "v" => VarSelected::V,
+                    "i" => VarSelected::I,
+                    _ => return Err(AppError::ProjectFileError),
+                };
+                false
+            }
+            "screen" => {
+                let name = next_arg_str(&mut args)?;
+                self.screen = match SCREENS.iter().find(|(n, _)| *n == name) {
+                    Some((_, item)) => Some(*item),
+                    None if name == "info" => None,
+                    None => return Err(AppError::ProjectFileError),
+                };
+                false
+            }
+            _ => return Err(AppError::ProjectFileError),
+        };
+
+        if args.next().is_some() {
+            Err(AppError::ProjectFileError)
+        } else {
+            Ok(setpoint)
+        }
+    }
+
+    /// Line `n` (0 based) of the file, false once it's the last one
+    pub fn write_line(&self, n: usize, buf: &mut String<U128>) -> Result<bool, AppError> {
+        match n {
+            0..=3 => {
+                let (key, vals) = if n % 2 == 0 {
+                    ("vset", &self.vset)
+                } else {
+                    ("iset", &self.iset)
+                };
+                write!(buf, "{} {} {:.3}\r\n", key, n / 2 + 1, vals[n / 2])?
+            }
+            4 => write!(
+                buf,
+                "select {} {}\r\n",
+                match self.chsel {
+                    ChSelected::Both => "both",
+                    ChSelected::Ch1 => "1",
+                    ChSelected::Ch2 => "2",
+                },
+                match self.vsel {
+                    VarSelected::V => "v",
+                    VarSelected::I => "i",
+                }
+            )?,
+            _ => write!(
+                buf,
+                "screen {}\r\n",
+                self.screen
+                    .and_then(|item| SCREENS.iter().find(|(_, i)| *i == item))
+                    .map_or("info", |(name, _)| name)
+            )?,
+        }
+
+        Ok(n < 5)
+    }
+}
+
+/// Screen a session restores to, none for screens that aren't (menu, files, programs)
+fn screen_of(ui: &UI) -> Option<Option<MenuItem>> {
+    match ui {
+        UI::InfoScreen(_) => Some(None),
+        UI::StatsScreen => Some(Some(MenuItem::Stats)),
+        UI::GraphScreen(gs) => Some(Some(MenuItem::Graph(gs.ch))),
+        UI::WaveformScreen(_) => Some(Some(MenuItem::Waveform)),
+        UI::ChargerScreen => Some(Some(MenuItem::Charger)),
+        UI::SweepScreen(_) => Some(Some(MenuItem::Sweep)),
+        UI::CalScreen(_) => Some(Some(MenuItem::Calibration)),
+        UI::SettingsScreen(_) => Some(Some(MenuItem::Settings)),
+        _ => None,
+    }
+}
+
+/// Follows the session, asks for a save once it settles
+pub struct SessionTracker {
+    pub enabled: bool, // not until the one at startup is restored (or not)
+    pub save_pending: bool,
+    current: Option<Session>,
+    saved: Option<Session>,
+    changed_at: Millis,
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        SessionTracker {
+            enabled: false,
+            save_pending: false,
+            current: None,
+            saved: None,
+            changed_at: 0,
+        }
+    }
+
+    /// Setpoints as polled, selection and screen from the UI
+    pub fn update(&mut self, now: Millis, ch1: &PSChannel, ch2: &PSChannel, ui: &UI) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut s = self.current.unwrap_or_else(Session::new);
+        match (ch1.vset, ch1.iset, ch2.vset, ch2.iset) {
+            (Some(v1), Some(i1), Some(v2), Some(i2)) => {
+                s.vset = [v1, v2];
+                s.iset = [i1, i2];
+            }
+            _ => return, // not polled yet
+        }
+        match ui {
+            UI::InfoScreen(is) => {
+                s.chsel = is.chsel;
+                s.vsel = is.vsel;
+            }
+            _ => (),
+        }
+        if let Some(screen) = screen_of(ui) {
+            s.screen = screen;
+        }
+
+        if self.current != Some(s) {
+            self.current = Some(s);
+            self.changed_at = self.changed_at.max(now); // a retry may be scheduled
+        }
+        self.save_pending = self.current != self.saved && now >= self.changed_at + SAVE_DELAY_MS;
+    }
+
+    /// Session to save
+    #[inline]
+    pub fn pending(&self) -> Option<Session> {
+        self.current.filter(|_| self.save_pending)
+    }
+
+    pub fn saved(&mut self, s: Session) {
+        self.saved = Some(s);
+        self.save_pending = false;
+    }
+
+    pub fn save_failed(&mut self, now: Millis) {
+        self.save_pending = false;
+        self.changed_at = now + RETRY_MS - SAVE_DELAY_MS;
+    }
+}
+
+/// Offered at startup
+pub struct RestoreScreen {
+    pub session: Session,
+    pub restore: bool, // answer, don't to begin with
+}
+
+impl RestoreScreen {
+    pub fn new(session: Session) -> Self {
+        RestoreScreen {
+            session,
+            restore: false,
+        }
+    }
+
+    /// Turn to pick, press to answer
+    pub fn handle_rotary_encoder(
+        &mut self,
+        re_press_duration: Option<MilliSeconds>,
+        short_press: MilliSeconds,
+        re_diff: i16,
+    ) -> Option<bool> {
+        if re_diff != 0 {
+            self.restore = re_diff > 0;
+        }
+        re_press_duration
+            .filter(|pd| pd > &short_press)
+            .map(|_| self.restore)
+    }
+}
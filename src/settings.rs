@@ -0,0 +1,437 @@
+//! Controller settings editable from the UI
+
+use core::fmt::Write;
+
+use heapless::{ArrayLength, String};
+
+use stm32f4xx_hal::time::MilliSeconds;
+
+use crate::{
+    limits::{I_MAX, V_MAX},
+    model::{VarSelected, PS},
+    prelude::*,
+    protocol::Channel,
+    sweep::SWEEP_MAX_POINTS,
+    waveform::*,
+};
+
+#[derive(Copy, Clone)]
+pub enum Setting {
+    SoftStart(Channel),
+    SoftStartV(Channel),
+    SoftStartTime(Channel),
+    Fuse(Channel),
+    FuseI(Channel),
+    FuseW(Channel),
+    FuseDelay(Channel),
+    AutoOff(Channel),
+    AutoOffTime(Channel),
+    LimitV(Channel),
+    LimitI(Channel),
+    LinkTimeout,
+    LinkSafeOff,
+    LogRun,
+    LogInterval,
+    WaveRun,
+    WaveChannel,
+    WaveTarget,
+    WaveShape,
+    WavePeriod,
+    WaveAmplitude,
+    WaveOffset,
+    WaveUpdate,
+    SweepRun,
+    SweepChannel,
+    SweepTarget,
+    SweepStart,
+    SweepStop,
+    SweepPoints,
+    SweepDwell,
+    SweepLimit,
+}
+
+/// In display order
+pub const SETTINGS: [Setting; 26] = [
+    Setting::LimitV(Channel::Ch1),
+    Setting::LimitI(Channel::Ch1),
+    Setting::LimitV(Channel::Ch2),
+    Setting::LimitI(Channel::Ch2),
+    Setting::SoftStart(Channel::Ch1),
+    Setting::SoftStartV(Channel::Ch1),
+    Setting::SoftStartTime(Channel::Ch1),
+    Setting::SoftStart(Channel::Ch2),
+    Setting::SoftStartV(Channel::Ch2),
+    Setting::SoftStartTime(Channel::Ch2),
+    Setting::Fuse(Channel::Ch1),
+    Setting::FuseI(Channel::Ch1),
+    Setting::FuseW(Channel::Ch1),
+    Setting::FuseDelay(Channel::Ch1),
+    Setting::Fuse(Channel::Ch2),
+    Setting::FuseI(Channel::Ch2),
+    Setting::FuseW(Channel::Ch2),
+    Setting::FuseDelay(Channel::Ch2),
+    Setting::AutoOff(Channel::Ch1),
+    Setting::AutoOffTime(Channel::Ch1),
+    Setting::AutoOff(Channel::Ch2),
+    Setting::AutoOffTime(Channel::Ch2),
+    Setting::LinkTimeout,
+    Setting::LinkSafeOff,
+    Setting::LogRun,
+    Setting::LogInterval,
+];
+
+/// Waveform screen
+pub const WAVE_SETTINGS: [Setting; 8] = [
+    Setting::WaveRun,
+    Setting::WaveChannel,
+    Setting::WaveTarget,
+    Setting::WaveShape,
+    Setting::WavePeriod,
+    Setting::WaveAmplitude,
+    Setting::WaveOffset,
+    Setting::WaveUpdate,
+];
+
+/// I-V sweep screen
+pub const SWEEP_SETTINGS: [Setting; 8] = [
+    Setting::SweepRun,
+    Setting::SweepChannel,
+    Setting::SweepTarget,
+    Setting::SweepStart,
+    Setting::SweepStop,
+    Setting::SweepPoints,
+    Setting::SweepDwell,
+    Setting::SweepLimit,
+];
+
+impl Setting {
+    pub fn write_label<S>(&self, buf: &mut String<S>) -> Result<(), AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        match self {
+            Setting::SoftStart(ch) => write!(buf, "{} soft start", ch.to_str())?,
+            Setting::SoftStartV(ch) => write!(buf, "{} ss from V", ch.to_str())?,
+            Setting::SoftStartTime(ch) => write!(buf, "{} ss ramp s", ch.to_str())?,
+            Setting::Fuse(ch) => write!(buf, "{} fuse", ch.to_str())?,
+            Setting::FuseI(ch) => write!(buf, "{} fuse A", ch.to_str())?,
+            Setting::FuseW(ch) => write!(buf, "{} fuse W", ch.to_str())?,
+            Setting::FuseDelay(ch) => write!(buf, "{} fuse ms", ch.to_str())?,
+            Setting::AutoOff(ch) => write!(buf, "{} auto-off", ch.to_str())?,
+            Setting::AutoOffTime(ch) => write!(buf, "{} off after m", ch.to_str())?,
+            Setting::LimitV(ch) => write!(buf, "{} max V", ch.to_str())?,
+            Setting::LimitI(ch) => write!(buf, "{} max A", ch.to_str())?,
+            Setting::LinkTimeout => write!(buf, "link lost s")?,
+            Setting::LinkSafeOff => write!(buf, "relink: out off")?,
+            Setting::LogRun => write!(buf, "log to SD")?,
+            Setting::LogInterval => write!(buf, "log every s")?,
+            Setting::WaveRun => write!(buf, "run")?,
+            Setting::WaveChannel => write!(buf, "channel")?,
+            Setting::WaveTarget => write!(buf, "modulate")?,
+            Setting::WaveShape => write!(buf, "shape")?,
+            Setting::WavePeriod => write!(buf, "period s")?,
+            Setting::WaveAmplitude => write!(buf, "amplitude")?,
+            Setting::WaveOffset => write!(buf, "offset")?,
+            Setting::WaveUpdate => write!(buf, "update ms")?,
+            Setting::SweepRun => write!(buf, "run")?,
+            Setting::SweepChannel => write!(buf, "channel")?,
+            Setting::SweepTarget => write!(buf, "step")?,
+            Setting::SweepStart => write!(buf, "start")?,
+            Setting::SweepStop => write!(buf, "stop")?,
+            Setting::SweepPoints => write!(buf, "points")?,
+            Setting::SweepDwell => write!(buf, "dwell s")?,
+            Setting::SweepLimit => write!(buf, "limit")?,
+        }
+        Ok(())
+    }
+
+    pub fn write_value<S>(&self, ps: &PS, buf: &mut String<S>) -> Result<(), AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        let v = self.value(ps);
+        match self {
+            Setting::SoftStart(_)
+            | Setting::Fuse(_)
+            | Setting::AutoOff(_)
+            | Setting::LinkSafeOff
+            | Setting::LogRun
+            | Setting::WaveRun
+            | Setting::SweepRun => write!(buf, "{}", if v > 0.5 { "on" } else { "off" })?,
+            Setting::WaveChannel => write!(buf, "{}", ps.waveform.ch.to_str())?,
+            Setting::WaveTarget => write!(
+                buf,
+                "{}",
+                match ps.waveform.target {
+                    VarSelected::V => "V",
+                    VarSelected::I => "I",
+                }
+            )?,
+            Setting::WaveShape => write!(buf, "{}", ps.waveform.shape.to_str())?,
+            Setting::SweepChannel => write!(buf, "{}", ps.sweep.ch.to_str())?,
+            Setting::SweepTarget => write!(
+                buf,
+                "{}",
+                match ps.sweep.target {
+                    VarSelected::V => "V",
+                    VarSelected::I => "I",
+                }
+            )?,
+            Setting::FuseI(_)
+            | Setting::LimitV(_)
+            | Setting::LimitI(_)
+            | Setting::WaveAmplitude
+            | Setting::WaveOffset
+            | Setting::SweepStart
+            | Setting::SweepStop
+            | Setting::SweepLimit => write!(buf, "{:.2}", v)?,
+            Setting::FuseW(_) if v == 0.0 => write!(buf, "off")?,
+            Setting::FuseDelay(_) | Setting::WaveUpdate | Setting::SweepPoints => {
+                write!(buf, "{:.0}", v)?
+            }
+            _ => write!(buf, "{:.1}", v)?,
+        }
+        Ok(())
+    }
+
+    /// Change by a number of encoder steps, clamp to the valid range
+    pub fn adjust(&self, ps: &mut PS, steps: f32) {
+        let (step, min, max) = self.limits();
+        let v = (self.value(ps) + step * steps).min(max).max(min);
+        self.set_value(ps, v);
+    }
+
+    /// (step, min, max)
+    fn limits(&self) -> (f32, f32, f32) {
+        match self {
+            Setting::SoftStart(_) => (1.0, 0.0, 1.0),
+            Setting::SoftStartV(_) => (0.1, 0.0, 20.0),
+            Setting::SoftStartTime(_) => (0.1, 0.1, 60.0),
+            Setting::Fuse(_) => (1.0, 0.0, 1.0),
+            Setting::FuseI(_) => (0.01, 0.0, 10.0),
+            Setting::FuseW(_) => (0.1, 0.0, 200.0),
+            Setting::FuseDelay(_) => (10.0, 0.0, 60_000.0),
+            Setting::AutoOff(_) => (1.0, 0.0, 1.0),
+            Setting::AutoOffTime(_) => (1.0, 1.0, 24.0 * 60.0),
+            Setting::LimitV(_) => (0.1, 0.0, V_MAX),
+            Setting::LimitI(_) => (0.01, 0.0, I_MAX),
+            Setting::LinkTimeout => (0.5, 1.0, 60.0),
+            Setting::LinkSafeOff => (1.0, 0.0, 1.0),
+            Setting::LogRun => (1.0, 0.0, 1.0),
+            Setting::LogInterval => (0.5, 0.5, 3600.0),
+            Setting::WaveRun => (1.0, 0.0, 1.0),
+            Setting::WaveChannel => (1.0, 1.0, 2.0),
+            Setting::WaveTarget => (1.0, 0.0, 1.0),
+            Setting::WaveShape => (1.0, 0.0, (SHAPES.len() - 1) as f32),
+            Setting::WavePeriod => (0.1, 0.1, 3600.0),
+            Setting::WaveAmplitude => (0.01, 0.0, 10.0),
+            Setting::WaveOffset => (0.01, 0.0, 20.0),
+            Setting::WaveUpdate => (10.0, 10.0, 10_000.0),
+            Setting::SweepRun => (1.0, 0.0, 1.0),
+            Setting::SweepChannel => (1.0, 1.0, 2.0),
+            Setting::SweepTarget => (1.0, 0.0, 1.0),
+            Setting::SweepStart | Setting::SweepStop => (0.01, 0.0, 20.0),
+            Setting::SweepPoints => (1.0, 2.0, SWEEP_MAX_POINTS as f32),
+            Setting::SweepDwell => (0.1, 0.1, 60.0),
+            Setting::SweepLimit => (0.01, 0.0, 20.0),
+        }
+    }
+
+    fn value(&self, ps: &PS) -> f32 {
+        match self {
+            Setting::SoftStart(ch) => {
+                if ps.channel(*ch).softstart.enabled {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Setting::SoftStartV(ch) => ps.channel(*ch).softstart.start_v,
+            Setting::SoftStartTime(ch) => ps.channel(*ch).softstart.ramp_ms as f32 / 1000.0,
+            Setting::Fuse(ch) => {
+                if ps.channel(*ch).efuse.enabled {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Setting::FuseI(ch) => ps.channel(*ch).efuse.trip_i,
+            Setting::FuseW(ch) => ps.channel(*ch).efuse.trip_w,
+            Setting::FuseDelay(ch) => ps.channel(*ch).efuse.delay_ms as f32,
+            Setting::AutoOff(ch) => {
+                if ps.channel(*ch).autooff.enabled {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Setting::AutoOffTime(ch) => ps.channel(*ch).autooff.duration_ms as f32 / 60_000.0,
+            Setting::LimitV(ch) => ps.channel(*ch).limits.v_max,
+            Setting::LimitI(ch) => ps.channel(*ch).limits.i_max,
+            Setting::LinkTimeout => ps.link.timeout_ms as f32 / 1000.0,
+            Setting::LinkSafeOff => {
+                if ps.link.safe_off {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Setting::LogRun => {
+                if ps.datalog.running {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Setting::LogInterval => ps.datalog.interval_ms as f32 / 1000.0,
+            Setting::WaveRun => {
+                if ps.waveform.running {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Setting::WaveChannel => match ps.waveform.ch {
+                Channel::Ch1 => 1.0,
+                Channel::Ch2 => 2.0,
+            },
+            Setting::WaveTarget => match ps.waveform.target {
+                VarSelected::V => 0.0,
+                VarSelected::I => 1.0,
+            },
+            Setting::WaveShape => SHAPES
+                .iter()
+                .position(|sh| *sh == ps.waveform.shape)
+                .unwrap_or(0) as f32,
+            Setting::WavePeriod => ps.waveform.period_ms as f32 / 1000.0,
+            Setting::WaveAmplitude => ps.waveform.amplitude,
+            Setting::WaveOffset => ps.waveform.offset,
+            Setting::WaveUpdate => ps.waveform.update_ms as f32,
+            Setting::SweepRun => {
+                if ps.sweep.run {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Setting::SweepChannel => match ps.sweep.ch {
+                Channel::Ch1 => 1.0,
+                Channel::Ch2 => 2.0,
+            },
+            Setting::SweepTarget => match ps.sweep.target {
+                VarSelected::V => 0.0,
+                VarSelected::I => 1.0,
+            },
+            Setting::SweepStart => ps.sweep.start,
+            Setting::SweepStop => ps.sweep.stop,
+            Setting::SweepPoints => ps.sweep.points as f32,
+            Setting::SweepDwell => ps.sweep.dwell_ms as f32 / 1000.0,
+            Setting::SweepLimit => ps.sweep.limit,
+        }
+    }
+
+    fn set_value(&self, ps: &mut PS, v: f32) {
+        match self {
+            Setting::SoftStart(ch) => ps.channel_mut(*ch).softstart.enabled = v > 0.5,
+            Setting::SoftStartV(ch) => ps.channel_mut(*ch).softstart.start_v = v,
+            Setting::SoftStartTime(ch) => {
+                ps.channel_mut(*ch).softstart.ramp_ms = (v * 1000.0) as u32
+            }
+            Setting::Fuse(ch) => ps.channel_mut(*ch).efuse.enabled = v > 0.5,
+            Setting::FuseI(ch) => ps.channel_mut(*ch).efuse.trip_i = v,
+            Setting::FuseW(ch) => ps.channel_mut(*ch).efuse.trip_w = v,
+            Setting::FuseDelay(ch) => ps.channel_mut(*ch).efuse.delay_ms = v as u32,
+            Setting::AutoOff(ch) => ps.channel_mut(*ch).autooff.enabled = v > 0.5,
+            Setting::AutoOffTime(ch) => {
+                ps.channel_mut(*ch).autooff.duration_ms = (v * 60_000.0) as u64
+            }
+            Setting::LimitV(ch) => ps.channel_mut(*ch).limits.v_max = v,
+            Setting::LimitI(ch) => ps.channel_mut(*ch).limits.i_max = v,
+            Setting::LinkTimeout => ps.link.timeout_ms = (v * 1000.0) as u32,
+            Setting::LinkSafeOff => ps.link.safe_off = v > 0.5,
+            Setting::LogRun => {
+                if v > 0.5 {
+                    ps.datalog.run()
+                } else {
+                    ps.datalog.stop()
+                }
+            }
+            Setting::LogInterval => ps.datalog.interval_ms = (v * 1000.0) as u32,
+            Setting::WaveRun => ps.waveform.running = v > 0.5,
+            Setting::WaveChannel => {
+                ps.waveform.ch = if v > 1.5 { Channel::Ch2 } else { Channel::Ch1 }
+            }
+            Setting::WaveTarget => {
+                ps.waveform.target = if v > 0.5 {
+                    VarSelected::I
+                } else {
+                    VarSelected::V
+                }
+            }
+            Setting::WaveShape => ps.waveform.shape = SHAPES[v as usize],
+            Setting::WavePeriod => ps.waveform.period_ms = (v * 1000.0) as u32,
+            Setting::WaveAmplitude => ps.waveform.amplitude = v,
+            Setting::WaveOffset => ps.waveform.offset = v,
+            Setting::WaveUpdate => ps.waveform.update_ms = v as u32,
+            Setting::SweepRun => ps.sweep.run = v > 0.5,
+            Setting::SweepChannel => {
+                ps.sweep.ch = if v > 1.5 { Channel::Ch2 } else { Channel::Ch1 }
+            }
+            Setting::SweepTarget => {
+                ps.sweep.target = if v > 0.5 {
+                    VarSelected::I
+                } else {
+                    VarSelected::V
+                }
+            }
+            Setting::SweepStart => ps.sweep.start = v,
+            Setting::SweepStop => ps.sweep.stop = v,
+            Setting::SweepPoints => ps.sweep.points = v as u16,
+            Setting::SweepDwell => ps.sweep.dwell_ms = (v * 1000.0) as u32,
+            Setting::SweepLimit => ps.sweep.limit = v,
+        }
+    }
+}
+
+/// Scroll through settings, press to start/stop editing the selected one
+pub struct SettingsScreen {
+    pub selected: usize,
+    pub editing: bool,
+}
+
+impl SettingsScreen {
+    pub fn new() -> Self {
+        SettingsScreen {
+            selected: 0,
+            editing: false,
+        }
+    }
+
+    /// Setting to adjust and by how many steps
+    pub fn handle_rotary_encoder(
+        &mut self,
+        items: &[Setting],
+        re_press_duration: Option<MilliSeconds>,
+        short_press: MilliSeconds,
+        re_pressed: bool,
+        re_diff: i16,
+    ) -> Option<(Setting, f32)> {
+        if re_press_duration.filter(|pd| pd > &short_press).is_some() {
+            self.editing = !self.editing;
+        }
+
+        if self.editing {
+            if re_diff != 0 {
+                let mul = if re_pressed { 10f32 } else { 1f32 };
+                Some((items[self.selected], mul * re_diff as f32))
+            } else {
+                None
+            }
+        } else {
+            self.selected = (self.selected as i16 + re_diff)
+                .max(0)
+                .min(items.len() as i16 - 1) as usize;
+            None
+        }
+    }
+}
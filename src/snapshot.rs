@@ -0,0 +1,109 @@
+//! Current setpoints saved as a project file.
+//!
+//! ```text
+//! # title: state 2026-10-18 12:00:00
+//! # channels: 1 2
+//! OVSET 1 6.000
+//! ISET 1 0.100
+//! VSET 1 5.000
+//! OUT 1 1
+//! OVSET 2 20.000
+//! ...
+//! ```
+//!
+//! Files are "SNAPnnn.GPB" in the root directory, loading one (file selector)
+//! restores the setpoints, outputs included.
+
+use core::fmt::Write;
+
+use heapless::{consts::*, String};
+
+use crate::{model::PSChannel, prelude::*, protocol::Channel, time::DateTime};
+
+pub const SNAPSHOT_PREFIX: &str = "SNAP";
+pub const SNAPSHOT_EXT: &str = "GPB";
+
+/// Header lines before the channel lines
+const HEADER_LINES: usize = 2;
+
+/// OVSET, ISET, VSET, OUT
+const CHANNEL_LINES: usize = 4;
+
+pub struct SaveScreen {
+    pub save_pending: bool,
+    pub saved: Option<String<U16>>, // file name, once it's written
+}
+
+impl SaveScreen {
+    /// Saves right away
+    pub fn new() -> Self {
+        SaveScreen {
+            save_pending: true,
+            saved: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Setpoints {
+    ovset: f32,
+    iset: f32,
+    vset: f32,
+    out: bool,
+}
+
+impl Setpoints {
+    fn new(psch: &PSChannel) -> Option<Self> {
+        Some(Setpoints {
+            ovset: psch.ovset?,
+            iset: psch.iset?,
+            vset: psch.vset?,
+            out: psch.out?,
+        })
+    }
+}
+
+/// Setpoints of both channels, as polled from the instrument
+pub struct Snapshot {
+    chs: [(Channel, Setpoints); 2],
+    time: Option<DateTime>,
+}
+
+impl Snapshot {
+    /// Every setpoint has to be known, a partial snapshot wouldn't restore the state
+    pub fn new(ch1: &PSChannel, ch2: &PSChannel, time: Option<DateTime>) -> Result<Self, AppError> {
+        match (Setpoints::new(ch1), Setpoints::new(ch2)) {
+            (Some(sp1), Some(sp2)) => Ok(Snapshot {
+                chs: [(Channel::Ch1, sp1), (Channel::Ch2, sp2)],
+                time,
+            }),
+            _ => Err(AppError::NoResponse),
+        }
+    }
+
+    /// Line `n` (0 based) of the file, false once it's the last one
+    pub fn write_line(&self, n: usize, buf: &mut String<U128>) -> Result<bool, AppError> {
+        match n {
+            0 => match self.time {
+                Some(dt) => write!(buf, "# title: state {}\r\n", dt)?,
+                None => write!(buf, "# title: state\r\n")?,
+            },
+            1 => write!(buf, "# channels: 1 2\r\n")?,
+            _ => {
+                let (ch, sp) = match self.chs.get((n - HEADER_LINES) / CHANNEL_LINES) {
+                    Some(c) => c,
+                    None => return Ok(false),
+                };
+                let ch = ch.to_str();
+                match (n - HEADER_LINES) % CHANNEL_LINES {
+                    0 => write!(buf, "OVSET {} {:.3}\r\n", ch, sp.ovset)?,
+                    1 => write!(buf, "ISET {} {:.3}\r\n", ch, sp.iset)?,
+                    2 => write!(buf, "VSET {} {:.3}\r\n", ch, sp.vset)?,
+                    _ => write!(buf, "OUT {} {}\r\n", ch, if sp.out { "1" } else { "0" })?,
+                }
+            }
+        }
+
+        Ok(n + 1 < HEADER_LINES + CHANNEL_LINES * self.chs.len())
+    }
+}
@@ -0,0 +1,87 @@
+//! Soft-start, ramp VSET up when the output is switched on
+//! (capacitive loads trip the current limit on a VSET step)
+
+use crate::clock::Millis;
+
+/// Interval between VSET updates while ramping
+pub const RAMP_STEP_MS: u32 = 100;
+
+/// Per channel soft-start settings
+#[derive(Copy, Clone)]
+pub struct SoftStart {
+    pub enabled: bool,
+    pub start_v: f32, // first VSET of the ramp
+    pub ramp_ms: u32, // time to reach the target VSET
+}
+
+impl SoftStart {
+    pub const fn new() -> Self {
+        SoftStart {
+            enabled: false,
+            start_v: 0.0,
+            ramp_ms: 1000,
+        }
+    }
+}
+
+/// VSET ramp in progress
+pub struct Ramp {
+    from: f32,
+    to: f32,
+    duration_ms: u32,
+    started: Option<Millis>, // on the first step
+    last_step: Millis,
+    progress: f32,
+}
+
+impl Ramp {
+    pub fn new(ss: &SoftStart, target: f32) -> Self {
+        Ramp {
+            from: ss.start_v.min(target),
+            to: target,
+            duration_ms: ss.ramp_ms.max(1),
+            started: None,
+            last_step: 0,
+            progress: 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn start_v(&self) -> f32 {
+        self.from
+    }
+
+    #[inline]
+    pub fn target(&self) -> f32 {
+        self.to
+    }
+
+    /// VSET changed while ramping, carry on towards the new one
+    pub fn retarget(&mut self, to: f32) {
+        self.from = self.from.min(to);
+        self.to = to;
+    }
+
+    /// 0 to 1
+    #[inline]
+    pub fn progress(&self) -> f32 {
+        self.progress
+    }
+
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.progress >= 1.0
+    }
+
+    /// Next VSET, None between steps
+    pub fn step(&mut self, now: Millis) -> Option<f32> {
+        let started = *self.started.get_or_insert(now);
+        if now < self.last_step + RAMP_STEP_MS as u64 {
+            return None;
+        }
+
+        self.last_step = now;
+        self.progress = ((now - started) as f32 / self.duration_ms as f32).min(1.0);
+        Some(self.from + (self.to - self.from) * self.progress)
+    }
+}
@@ -0,0 +1,86 @@
+//! Running measurement statistics
+
+/// Min/max/mean of readings since last reset
+#[derive(Clone, Copy)]
+pub struct Stats {
+    min: f32,
+    max: f32,
+    sum: f64, // many samples in a long window, keep precision
+    count: u32,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    #[inline]
+    pub fn reset(&mut self) {
+        *self = Stats::new()
+    }
+
+    pub fn add(&mut self, v: f32) {
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+        self.sum += v as f64;
+        self.count = self.count.saturating_add(1);
+    }
+
+    #[inline]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    #[inline]
+    pub fn min(&self) -> Option<f32> {
+        self.if_sampled(self.min)
+    }
+
+    #[inline]
+    pub fn max(&self) -> Option<f32> {
+        self.if_sampled(self.max)
+    }
+
+    #[inline]
+    pub fn mean(&self) -> Option<f32> {
+        self.if_sampled((self.sum / self.count.max(1) as f64) as f32)
+    }
+
+    #[inline]
+    fn if_sampled(&self, v: f32) -> Option<f32> {
+        if self.count > 0 {
+            Some(v)
+        } else {
+            None
+        }
+    }
+}
+
+/// Stats of all readings of a single channel
+pub struct ChannelStats {
+    pub vout: Stats,
+    pub iout: Stats,
+    pub pout: Stats,
+}
+
+impl ChannelStats {
+    pub const fn new() -> Self {
+        ChannelStats {
+            vout: Stats::new(),
+            iout: Stats::new(),
+            pout: Stats::new(),
+        }
+    }
+
+    /// Start a new window
+    pub fn reset(&mut self) {
+        self.vout.reset();
+        self.iout.reset();
+        self.pout.reset();
+    }
+}
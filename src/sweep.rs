@@ -0,0 +1,257 @@
+//! I-V sweep, steps VSET (or ISET) across a range and records VOUT/IOUT at each point
+
+use core::fmt::Write;
+
+use heapless::{consts::*, ArrayLength, String, Vec};
+
+use crate::{
+    clock::Millis,
+    model::{PSChannel, VarSelected},
+    prelude::*,
+    protocol::*,
+    settings::SettingsScreen,
+};
+
+pub const SWEEP_MAX_POINTS: u16 = 100;
+
+/// Sweep settings
+#[derive(Copy, Clone)]
+pub struct SweepParams {
+    pub run: bool,
+    pub ch: Channel,
+    pub target: VarSelected, // what's stepped
+    pub start: f32,
+    pub stop: f32,
+    pub points: u16,
+    pub dwell_ms: u32,
+    pub limit: f32, // the other setpoint (ISET when stepping VSET)
+}
+
+impl SweepParams {
+    pub fn new() -> Self {
+        SweepParams {
+            run: false,
+            ch: Channel::Ch1,
+            target: VarSelected::V,
+            start: 0.0,
+            stop: 5.0,
+            points: 51,
+            dwell_ms: 1000,
+            limit: 0.1,
+        }
+    }
+
+    /// Setpoint of a point
+    pub fn setpoint(&self, idx: u16) -> f32 {
+        let n = self.points.max(2) - 1;
+        self.start + (self.stop - self.start) * idx as f32 / n as f32
+    }
+
+    fn setpoint_cmd(&self, val: f32) -> Command {
+        match self.target {
+            VarSelected::V => Command::Vset { ch: self.ch, val },
+            VarSelected::I => Command::Iset { ch: self.ch, val },
+        }
+    }
+
+    fn limit_cmd(&self) -> Command {
+        match self.target {
+            VarSelected::V => Command::Iset {
+                ch: self.ch,
+                val: self.limit,
+            },
+            VarSelected::I => Command::Vset {
+                ch: self.ch,
+                val: self.limit,
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct SweepPoint {
+    pub set: f32,
+    pub vout: f32,
+    pub iout: f32,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum SweepState {
+    Idle,
+    Running,
+    Done,
+}
+
+/// Sweep in progress and its results
+pub struct SweepRun {
+    pub state: SweepState,
+    pub active: SweepParams, // copy taken at the start
+    pub points: Vec<SweepPoint, U100>,
+    pub streamed: usize,    // points sent over USB
+    pub save_pending: bool, // results to write to the SD card
+    pub saved: Option<String<U16>>,
+    set_at: Millis,
+    marks: Option<(u32, u32)>, // reading counts once the dwell is over
+}
+
+impl SweepRun {
+    pub fn new() -> Self {
+        SweepRun {
+            state: SweepState::Idle,
+            active: SweepParams::new(),
+            points: Vec::new(),
+            streamed: 0,
+            save_pending: false,
+            saved: None,
+            set_at: 0,
+            marks: None,
+        }
+    }
+
+    /// Channel the sweep is (or is about to be) running on
+    #[inline]
+    pub fn channel(&self, params: &SweepParams) -> Channel {
+        match self.state {
+            SweepState::Running => self.active.ch,
+            _ => params.ch,
+        }
+    }
+
+    /// Periodic update, starts/stops on the `run` setting, steps through the points
+    pub fn tick(
+        &mut self,
+        now: Millis,
+        params: &mut SweepParams,
+        psch: &mut PSChannel,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        match (params.run, self.state) {
+            (true, SweepState::Idle) | (true, SweepState::Done) => {
+                return self.start(now, params, psch, cmds)
+            }
+            (false, SweepState::Running) => return self.finish(psch, cmds),
+            (false, _) => return Ok(()),
+            _ => (),
+        }
+
+        if now < self.set_at + self.active.dwell_ms as u64 {
+            return Ok(());
+        }
+
+        // wait for fresh readings taken after the dwell
+        let (vmark, imark) = *self.marks.get_or_insert((psch.vout_n, psch.iout_n));
+        if psch.vout_n == vmark || psch.iout_n == imark {
+            return Ok(());
+        }
+
+        let idx = self.points.len() as u16;
+        match psch.vout.zip(psch.iout) {
+            Some((vout, iout)) => self
+                .points
+                .push(SweepPoint {
+                    set: self.active.setpoint(idx),
+                    vout,
+                    iout,
+                })
+                .map_err(|_| AppError::Duh)?,
+            None => return Ok(()),
+        }
+
+        if self.points.len() as u16 >= self.active.points {
+            params.run = false;
+            self.finish(psch, cmds)
+        } else {
+            self.set_point(now, idx + 1, cmds)
+        }
+    }
+
+    fn start(
+        &mut self,
+        now: Millis,
+        params: &SweepParams,
+        psch: &mut PSChannel,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        self.active = *params;
+        self.active.points = self.active.points.max(2).min(SWEEP_MAX_POINTS);
+        self.points.clear();
+        self.streamed = 0;
+        self.save_pending = false;
+        self.saved = None;
+        self.state = SweepState::Running;
+
+        psch.ramp = None;
+        cmds.push(self.active.limit_cmd())?;
+        self.set_point(now, 0, cmds)?;
+        cmds.push(Command::Out {
+            ch: self.active.ch,
+            on: true,
+        })?;
+        psch.out = None;
+        Ok(())
+    }
+
+    fn set_point(
+        &mut self,
+        now: Millis,
+        idx: u16,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        cmds.push(self.active.setpoint_cmd(self.active.setpoint(idx)))?;
+        self.set_at = now;
+        self.marks = None;
+        Ok(())
+    }
+
+    /// Done (or stopped), switch the output off, save what's been recorded
+    fn finish(&mut self, psch: &mut PSChannel, cmds: &mut CommandQueue) -> Result<(), AppError> {
+        cmds.push(Command::Out {
+            ch: self.active.ch,
+            on: false,
+        })?;
+        psch.out = None;
+        self.state = SweepState::Done;
+        self.save_pending = !self.points.is_empty();
+        Ok(())
+    }
+
+    /// CSV header
+    pub fn write_csv_header<S>(&self, buf: &mut String<S>) -> Result<(), AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        let set = match self.active.target {
+            VarSelected::V => "vset",
+            VarSelected::I => "iset",
+        };
+        write!(buf, "{},vout,iout\r\n", set)?;
+        Ok(())
+    }
+
+    /// CSV line of a point
+    pub fn write_csv_line<S>(&self, idx: usize, buf: &mut String<S>) -> Result<(), AppError>
+    where
+        S: ArrayLength<u8>,
+    {
+        let p = &self.points[idx];
+        write!(buf, "{:.3},{:.4},{:.4}\r\n", p.set, p.vout, p.iout)?;
+        Ok(())
+    }
+}
+
+/// Sweep settings list, plot of the results once started
+pub struct SweepScreen {
+    pub list: SettingsScreen,
+    pub run: SweepRun,
+    pub show_plot: bool,
+}
+
+impl SweepScreen {
+    pub fn new() -> Self {
+        SweepScreen {
+            list: SettingsScreen::new(),
+            run: SweepRun::new(),
+            show_plot: false,
+        }
+    }
+}
@@ -1,9 +1,236 @@
-use embedded_sdmmc::*;
+//! Wall clock time, kept by the STM32 RTC (clocked by the LSE crystal,
+//! it keeps going on VBAT while the controller is off)
 
-pub struct DummyTimeSource {}
+use core::fmt;
 
-impl TimeSource for DummyTimeSource {
+use embedded_sdmmc::{TimeSource, Timestamp};
+
+use stm32f4xx_hal::stm32::{PWR, RCC, RTC};
+
+use crate::prelude::*;
+
+/// Give up on the LSE crystal (or the RTC) after this many polls
+const RTC_TIMEOUT: u32 = 1_000_000;
+
+/// Calendar date and time (years 2001 to 2099, the RTC only has two digits)
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// "YYYY-MM-DD" "HH:MM[:SS]"
+    pub fn parse(date: &str, time: &str) -> Result<Self, AppError> {
+        let mut d = date.split('-');
+        let mut t = time.split(':');
+        let dt = DateTime {
+            year: parse_field(d.next())?,
+            month: parse_field(d.next())?,
+            day: parse_field(d.next())?,
+            hour: parse_field(t.next())?,
+            minute: parse_field(t.next())?,
+            second: t.next().map_or(Ok(0), |s| parse_field(Some(s)))?,
+        };
+
+        let valid = d.next().is_none()
+            && t.next().is_none()
+            && dt.year > 2000
+            && dt.year < 2100
+            && dt.month >= 1
+            && dt.month <= 12
+            && dt.day >= 1
+            && dt.day <= days_in_month(dt.year, dt.month)
+            && dt.hour < 24
+            && dt.minute < 60
+            && dt.second < 60;
+
+        if valid {
+            Ok(dt)
+        } else {
+            Err(AppError::ParseError)
+        }
+    }
+
+    /// 1 (Monday) to 7, as the RTC counts them
+    fn weekday(&self) -> u8 {
+        // Sakamoto's method, 0 is Sunday
+        const T: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let y = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let wd = (y + y / 4 - y / 100 + y / 400 + T[self.month as usize - 1] + self.day as u16) % 7;
+        if wd == 0 {
+            7
+        } else {
+            wd as u8
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn parse_field<T: core::str::FromStr>(s: Option<&str>) -> Result<T, AppError> {
+    s.and_then(|s| s.parse().ok()).ok_or(AppError::ParseError)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[inline]
+fn bcd(v: u8) -> u32 {
+    (((v / 10) << 4) | (v % 10)) as u32
+}
+
+#[inline]
+fn from_bcd(b: u32) -> u8 {
+    ((b >> 4) * 10 + (b & 0xf)) as u8
+}
+
+/// Handle of the RTC registers, also the FAT time source.
+/// Copies are handed out, they're only used from the idle loop.
+#[derive(Copy, Clone)]
+pub struct Rtc {
+    enabled: bool, // LSE is running
+}
+
+impl Rtc {
+    /// Clock the RTC from LSE (unless it already is), the calendar survives resets
+    pub fn init(_rtc: RTC) -> Self {
+        // NOTE(unsafe) This executes only during initialisation
+        let rcc = unsafe { &(*RCC::ptr()) };
+        let pwr = unsafe { &(*PWR::ptr()) };
+
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit()); // backup domain write access
+
+        if rcc.bdcr.read().rtcen().bit_is_clear() {
+            rcc.bdcr.modify(|_, w| w.lseon().set_bit());
+            let mut tries = RTC_TIMEOUT;
+            while rcc.bdcr.read().lserdy().bit_is_clear() && tries > 0 {
+                tries -= 1;
+            }
+            if tries == 0 {
+                return Rtc { enabled: false }; // no crystal, no clock
+            }
+            rcc.bdcr
+                .modify(|_, w| unsafe { w.rtcsel().bits(0b01) }.rtcen().set_bit());
+        }
+
+        let rtc = Rtc { enabled: true };
+        rtc.unlocked(|r| r.isr.modify(|_, w| w.rsf().clear_bit())); // shadow registers resync
+        rtc
+    }
+
+    /// The calendar was set since the backup domain lost power
+    pub fn is_set(&self) -> bool {
+        // NOTE(unsafe) read only
+        self.enabled && unsafe { &(*RTC::ptr()) }.isr.read().inits().bit_is_set()
+    }
+
+    pub fn now(&self) -> Option<DateTime> {
+        if !self.is_set() {
+            return None;
+        }
+
+        // NOTE(unsafe) read only, DR is locked in the shadow register once TR is read
+        let rtc = unsafe { &(*RTC::ptr()) };
+
+        // shadow registers are stale until they resync after init or `set`
+        let mut tries = RTC_TIMEOUT;
+        while rtc.isr.read().rsf().bit_is_clear() {
+            if tries == 0 {
+                return None;
+            }
+            tries -= 1;
+        }
+
+        let tr = rtc.tr.read().bits();
+        let dr = rtc.dr.read().bits();
+
+        Some(DateTime {
+            year: 2000 + from_bcd((dr >> 16) & 0xff) as u16,
+            month: from_bcd((dr >> 8) & 0x1f),
+            day: from_bcd(dr & 0x3f),
+            hour: from_bcd((tr >> 16) & 0x3f),
+            minute: from_bcd((tr >> 8) & 0x7f),
+            second: from_bcd(tr & 0x7f),
+        })
+    }
+
+    /// Set the calendar (24 hour format, prescalers are at their 1Hz reset values)
+    pub fn set(&self, dt: &DateTime) -> Result<(), AppError> {
+        if !self.enabled {
+            return Err(AppError::Duh);
+        }
+
+        let tr = (bcd(dt.hour) << 16) | (bcd(dt.minute) << 8) | bcd(dt.second);
+        let dr = (bcd((dt.year - 2000) as u8) << 16)
+            | ((dt.weekday() as u32) << 13)
+            | (bcd(dt.month) << 8)
+            | bcd(dt.day);
+
+        self.unlocked(|rtc| {
+            rtc.isr.modify(|_, w| w.init().set_bit());
+            let mut tries = RTC_TIMEOUT;
+            while rtc.isr.read().initf().bit_is_clear() {
+                if tries == 0 {
+                    rtc.isr.modify(|_, w| w.init().clear_bit());
+                    return Err(AppError::Duh);
+                }
+                tries -= 1;
+            }
+
+            rtc.tr.write(|w| unsafe { w.bits(tr) });
+            rtc.dr.write(|w| unsafe { w.bits(dr) });
+            rtc.isr
+                .modify(|_, w| w.init().clear_bit().rsf().clear_bit());
+            Ok(())
+        })
+    }
+
+    /// Run with the RTC registers write protection off
+    fn unlocked<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&stm32f4xx_hal::stm32::rtc::RegisterBlock) -> R,
+    {
+        // NOTE(unsafe) only used from the idle loop (and init)
+        let rtc = unsafe { &(*RTC::ptr()) };
+        rtc.wpr.write(|w| unsafe { w.bits(0xca) });
+        rtc.wpr.write(|w| unsafe { w.bits(0x53) });
+        let res = f(rtc);
+        rtc.wpr.write(|w| unsafe { w.bits(0xff) });
+        res
+    }
+}
+
+impl TimeSource for Rtc {
     fn get_timestamp(&self) -> Timestamp {
-        Timestamp::from_calendar(2022, 1, 1, 0, 0, 0).unwrap()
+        self.now()
+            .and_then(|dt| {
+                Timestamp::from_calendar(dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second)
+                    .ok()
+            })
+            .unwrap_or_else(|| Timestamp::from_calendar(2022, 1, 1, 0, 0, 0).unwrap())
     }
 }
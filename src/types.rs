@@ -42,7 +42,7 @@ pub type SDCardSPI = embedded_sdmmc::SdMmcSpi<
     gpio::gpiob::PB12<gpio::Output<gpio::PushPull>>,
 >;
 
-pub type SDCardController = embedded_sdmmc::Controller<SDCardSPI, DummyTimeSource>;
+pub type SDCardController = embedded_sdmmc::Controller<SDCardSPI, Rtc>;
 
 pub type PauseButtonPin = gpioa::PA1<Input<PullUp>>;
 
@@ -0,0 +1,255 @@
+//! Function generator, modulates VSET or ISET of a channel
+
+use heapless::{consts::*, Vec};
+
+use crate::{
+    clock::Millis,
+    limits::SoftLimits,
+    link::Link,
+    model::VarSelected,
+    prelude::*,
+    protocol::*,
+    settings::SettingsScreen,
+};
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Shape {
+    Sine,
+    Square,
+    Triangle,
+    Sawtooth,
+    Table,
+}
+
+pub const SHAPES: [Shape; 5] = [
+    Shape::Sine,
+    Shape::Square,
+    Shape::Triangle,
+    Shape::Sawtooth,
+    Shape::Table,
+];
+
+impl Shape {
+    pub fn parse(s: &str) -> Result<Self, AppError> {
+        SHAPES
+            .iter()
+            .find(|sh| sh.to_str() == s)
+            .copied()
+            .ok_or(AppError::ProjectFileError)
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Shape::Sine => "sine",
+            Shape::Square => "square",
+            Shape::Triangle => "triangle",
+            Shape::Sawtooth => "saw",
+            Shape::Table => "table",
+        }
+    }
+
+    /// -1 to 1, phase is 0 to 1
+    fn value(&self, phase: f32, table: &[f32]) -> f32 {
+        match self {
+            Shape::Sine => sin_turns(phase),
+            Shape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Shape::Triangle => {
+                if phase < 0.25 {
+                    4.0 * phase
+                } else if phase < 0.75 {
+                    2.0 - 4.0 * phase
+                } else {
+                    4.0 * phase - 4.0
+                }
+            }
+            Shape::Sawtooth => 2.0 * phase - 1.0,
+            // points are spread evenly over the period and held
+            Shape::Table => {
+                if table.is_empty() {
+                    0.0
+                } else {
+                    table[((phase * table.len() as f32) as usize).min(table.len() - 1)]
+                }
+            }
+        }
+    }
+}
+
+/// Sine of a phase given in turns (0 to 1), no libm here
+fn sin_turns(phase: f32) -> f32 {
+    // fold into -1/4..1/4 turn where the series converges quickly
+    let x = if phase < 0.25 {
+        phase
+    } else if phase < 0.75 {
+        0.5 - phase
+    } else {
+        phase - 1.0
+    };
+
+    let t = 2.0 * core::f32::consts::PI * x;
+    let t2 = t * t;
+    t * (1.0 - t2 / 6.0 * (1.0 - t2 / 20.0 * (1.0 - t2 / 42.0 * (1.0 - t2 / 72.0))))
+}
+
+/// Waveform settings
+pub struct Waveform {
+    pub running: bool,
+    pub ch: Channel,
+    pub target: VarSelected,
+    pub shape: Shape,
+    pub period_ms: u32,
+    pub amplitude: f32,
+    pub offset: f32,
+    pub update_ms: u32, // requested, the link may not keep up
+    pub table: Vec<f32, U32>,
+}
+
+impl Waveform {
+    pub fn new() -> Self {
+        Waveform {
+            running: false,
+            ch: Channel::Ch1,
+            target: VarSelected::V,
+            shape: Shape::Sine,
+            period_ms: 10_000,
+            amplitude: 1.0,
+            offset: 5.0,
+            update_ms: 100,
+            table: Vec::new(),
+        }
+    }
+
+    /// Setpoint at a time since start, within the channel's soft limits
+    pub fn value(&self, t_ms: u64, lim: &SoftLimits) -> f32 {
+        let period = self.period_ms.max(1) as u64;
+        let phase = (t_ms % period) as f32 / period as f32;
+        clamp(
+            self.offset + self.amplitude * self.shape.value(phase, &self.table),
+            self.target,
+            lim,
+        )
+    }
+}
+
+/// Setpoints over the soft limits would be dropped (and reported) on every update
+fn clamp(v: f32, target: VarSelected, lim: &SoftLimits) -> f32 {
+    let max = match target {
+        VarSelected::V => lim.v_max,
+        VarSelected::I => lim.i_max,
+    };
+    v.min(max).max(0.0)
+}
+
+/// Streams setpoints of a running waveform
+pub struct WaveGen {
+    started: Option<Millis>,
+    last_update: Millis,
+    driving: Option<(Channel, VarSelected)>, // output being modulated
+    pub value: Option<f32>,                  // last sent
+    pub update_ms: u32,                      // effective update interval
+}
+
+impl WaveGen {
+    pub fn new() -> Self {
+        WaveGen {
+            started: None,
+            last_update: 0,
+            driving: None,
+            value: None,
+            update_ms: 0,
+        }
+    }
+
+    /// Periodic update, sends a new setpoint when it's due
+    pub fn tick(
+        &mut self,
+        now: Millis,
+        wf: &Waveform,
+        link: &Link,
+        l1: &SoftLimits,
+        l2: &SoftLimits,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        self.update_ms = wf.update_ms.max(link.min_update_ms());
+
+        // park the previous output when stopped or retargeted
+        match self.driving {
+            Some((ch, target)) if !wf.running || ch != wf.ch || target != wf.target => {
+                self.stop(wf, l1, l2, cmds)?
+            }
+            _ => (),
+        }
+
+        if !wf.running {
+            return Ok(());
+        }
+
+        let started = *self.started.get_or_insert(now);
+        if self.driving.is_some() && now < self.last_update + self.update_ms as u64 {
+            return Ok(());
+        }
+
+        let lim = match wf.ch {
+            Channel::Ch1 => l1,
+            Channel::Ch2 => l2,
+        };
+        let v = wf.value(now - started, lim);
+        cmds.push(setpoint_cmd(wf.ch, wf.target, v))?;
+
+        self.driving = Some((wf.ch, wf.target));
+        self.last_update = now;
+        self.value = Some(v);
+        Ok(())
+    }
+
+    /// Leave the modulated output at the waveform offset
+    pub fn stop(
+        &mut self,
+        wf: &Waveform,
+        l1: &SoftLimits,
+        l2: &SoftLimits,
+        cmds: &mut CommandQueue,
+    ) -> Result<(), AppError> {
+        match self.driving.take() {
+            Some((ch, target)) => {
+                let lim = match ch {
+                    Channel::Ch1 => l1,
+                    Channel::Ch2 => l2,
+                };
+                cmds.push(setpoint_cmd(ch, target, clamp(wf.offset, target, lim)))?
+            }
+            None => (),
+        }
+        self.started = None;
+        self.value = None;
+        Ok(())
+    }
+}
+
+fn setpoint_cmd(ch: Channel, target: VarSelected, val: f32) -> Command {
+    match target {
+        VarSelected::V => Command::Vset { ch, val },
+        VarSelected::I => Command::Iset { ch, val },
+    }
+}
+
+/// Waveform settings list, generator runs while the screen is open
+pub struct WaveformScreen {
+    pub list: SettingsScreen,
+    pub gen: WaveGen,
+}
+
+impl WaveformScreen {
+    pub fn new() -> Self {
+        WaveformScreen {
+            list: SettingsScreen::new(),
+            gen: WaveGen::new(),
+        }
+    }
+}